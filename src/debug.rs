@@ -0,0 +1,259 @@
+//! Checks on streaming mappings, like Linux `CONFIG_DMA_API_DEBUG`.
+//!
+//! Every map, unmap and sync made through a [`crate::DmaDevice`] is tracked
+//! in a registry of live mappings, and misuse is passed to the sink set with
+//! [`set_sink`]. Reports are dropped until a sink is set.
+
+use alloc::vec::Vec;
+use core::{fmt, ptr::NonNull};
+
+use spin::Mutex;
+
+use crate::Direction;
+
+/// Who may access a mapping, flipped by `sync_for_cpu` and `sync_for_device`.
+///
+/// A new mapping belongs to the cpu. Writes through the buffer types only
+/// write their cache lines back, the device gets the mapping once it is
+/// synced for it and the cpu may not write it again before syncing it back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Cpu,
+    Device,
+}
+
+/// One misuse found by the checker, addresses are cpu addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugReport {
+    /// unmap of memory that is not mapped, e.g. unmapped twice
+    DoubleUnmap {
+        addr: usize,
+        size: usize,
+    },
+    SizeMismatch {
+        addr: usize,
+        mapped: usize,
+        unmapped: usize,
+    },
+    SyncOutsideMapping {
+        addr: usize,
+        size: usize,
+    },
+    CpuWriteFromDevice {
+        addr: usize,
+        size: usize,
+    },
+    /// cpu write to a mapping handed to the device and not synced back
+    CpuWriteDeviceOwned {
+        addr: usize,
+        size: usize,
+    },
+    /// unmap of a mapping the device may have written since its last sync for the cpu
+    UnmapDeviceOwned {
+        addr: usize,
+        size: usize,
+    },
+    /// a new mapping overlaps the live one at `other`
+    Overlap {
+        addr: usize,
+        size: usize,
+        other: usize,
+        other_size: usize,
+    },
+    /// still mapped at [`checkpoint`]
+    Leaked {
+        addr: usize,
+        size: usize,
+        direction: Direction,
+        owner: Owner,
+    },
+}
+
+impl fmt::Display for DebugReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DebugReport::DoubleUnmap { addr, size } => {
+                write!(f, "unmap of {addr:#x} size {size:#x} which is not mapped")
+            }
+            DebugReport::SizeMismatch {
+                addr,
+                mapped,
+                unmapped,
+            } => write!(
+                f,
+                "unmap of {addr:#x} with size {unmapped:#x}, mapped with {mapped:#x}"
+            ),
+            DebugReport::SyncOutsideMapping { addr, size } => {
+                write!(f, "sync of {addr:#x} size {size:#x} outside any mapping")
+            }
+            DebugReport::CpuWriteFromDevice { addr, size } => write!(
+                f,
+                "cpu write to {addr:#x} size {size:#x} of a FromDevice mapping"
+            ),
+            DebugReport::CpuWriteDeviceOwned { addr, size } => write!(
+                f,
+                "cpu write to {addr:#x} size {size:#x} while the device owns it"
+            ),
+            DebugReport::UnmapDeviceOwned { addr, size } => write!(
+                f,
+                "unmap of {addr:#x} size {size:#x} never synced back for the cpu"
+            ),
+            DebugReport::Overlap {
+                addr,
+                size,
+                other,
+                other_size,
+            } => write!(
+                f,
+                "mapping {addr:#x} size {size:#x} overlaps {other:#x} size {other_size:#x}"
+            ),
+            DebugReport::Leaked {
+                addr,
+                size,
+                direction,
+                owner,
+            } => write!(
+                f,
+                "{direction:?} mapping {addr:#x} size {size:#x} still alive, owned by {owner:?}"
+            ),
+        }
+    }
+}
+
+/// Where reports go, e.g. the kernel log.
+pub trait DebugSink: Sync {
+    fn report(&self, report: &DebugReport);
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    addr: usize,
+    size: usize,
+    direction: Direction,
+    owner: Owner,
+}
+
+impl Mapping {
+    fn contains(&self, addr: usize, size: usize) -> bool {
+        addr >= self.addr && addr + size <= self.addr + self.size
+    }
+}
+
+static MAPPINGS: Mutex<Vec<Mapping>> = Mutex::new(Vec::new());
+static SINK: Mutex<Option<&'static dyn DebugSink>> = Mutex::new(None);
+
+/// Send reports to `sink`, replacing the previous one.
+pub fn set_sink(sink: &'static dyn DebugSink) {
+    *SINK.lock() = Some(sink);
+}
+
+/// Report every live mapping as [`DebugReport::Leaked`], returns how many there are.
+pub fn checkpoint() -> usize {
+    let leaked: Vec<_> = MAPPINGS
+        .lock()
+        .iter()
+        .map(|m| DebugReport::Leaked {
+            addr: m.addr,
+            size: m.size,
+            direction: m.direction,
+            owner: m.owner,
+        })
+        .collect();
+    leaked.iter().for_each(emit);
+    leaked.len()
+}
+
+/// Called outside the registry lock, the sink may use the dma api itself.
+fn emit(report: &DebugReport) {
+    let sink = *SINK.lock();
+    if let Some(sink) = sink {
+        sink.report(report);
+    }
+}
+
+pub(crate) fn on_map(addr: NonNull<u8>, size: usize, direction: Direction) {
+    let addr = addr.as_ptr() as usize;
+    let overlap = {
+        let mut mappings = MAPPINGS.lock();
+        let overlap = mappings
+            .iter()
+            .find(|m| addr < m.addr + m.size && m.addr < addr + size)
+            .map(|m| DebugReport::Overlap {
+                addr,
+                size,
+                other: m.addr,
+                other_size: m.size,
+            });
+        mappings.push(Mapping {
+            addr,
+            size,
+            direction,
+            owner: Owner::Cpu,
+        });
+        overlap
+    };
+    overlap.iter().for_each(emit);
+}
+
+pub(crate) fn on_unmap(addr: NonNull<u8>, size: usize) {
+    let addr = addr.as_ptr() as usize;
+    let report = {
+        let mut mappings = MAPPINGS.lock();
+        // prefer an exact match when the same memory is mapped more than once
+        let i = mappings
+            .iter()
+            .position(|m| m.addr == addr && m.size == size)
+            .or_else(|| mappings.iter().position(|m| m.addr == addr));
+        match i.map(|i| mappings.swap_remove(i)) {
+            None => Some(DebugReport::DoubleUnmap { addr, size }),
+            Some(m) if m.size != size => Some(DebugReport::SizeMismatch {
+                addr,
+                mapped: m.size,
+                unmapped: size,
+            }),
+            Some(m) if m.owner == Owner::Device && m.direction != Direction::ToDevice => {
+                Some(DebugReport::UnmapDeviceOwned { addr, size })
+            }
+            Some(_) => None,
+        }
+    };
+    report.iter().for_each(emit);
+}
+
+pub(crate) fn on_sync(addr: NonNull<u8>, size: usize, owner: Owner) {
+    if size == 0 {
+        return;
+    }
+    let addr = addr.as_ptr() as usize;
+    let found = {
+        let mut mappings = MAPPINGS.lock();
+        mappings
+            .iter_mut()
+            .filter(|m| m.contains(addr, size))
+            .fold(false, |_, m| {
+                m.owner = owner;
+                true
+            })
+    };
+    if !found {
+        emit(&DebugReport::SyncOutsideMapping { addr, size });
+    }
+}
+
+pub(crate) fn on_cpu_write(addr: NonNull<u8>, size: usize) {
+    let addr = addr.as_ptr() as usize;
+    let report = MAPPINGS
+        .lock()
+        .iter()
+        .filter(|m| m.contains(addr, size))
+        .find_map(|m| {
+            if m.direction == Direction::FromDevice {
+                Some(DebugReport::CpuWriteFromDevice { addr, size })
+            } else if m.owner == Owner::Device {
+                Some(DebugReport::CpuWriteDeviceOwned { addr, size })
+            } else {
+                None
+            }
+        });
+    report.iter().for_each(emit);
+}
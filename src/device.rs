@@ -0,0 +1,254 @@
+use core::ptr::NonNull;
+
+use crate::{get_osal, CoherentMem, DError, Direction, Osal};
+
+/// The DMA context of one device.
+///
+/// Every mapping made through a `DmaDevice` goes to its own [`Osal`], so a
+/// device behind an IOMMU can use an `Osal` for its IOMMU domain while other
+/// devices are mapped directly. The `*_in` constructors of `DVec`, `DBox`,
+/// `DSlice` and `DVecPool` take a device, the others use [`DmaDevice::global`].
+#[derive(Clone, Copy)]
+pub struct DmaDevice {
+    osal: &'static dyn Osal,
+    dma_mask: u64,
+    coherent: bool,
+    cache_line: usize,
+    strict_alignment: bool,
+}
+
+impl DmaDevice {
+    pub fn new(osal: &'static dyn Osal, dma_mask: u64) -> Self {
+        Self {
+            osal,
+            dma_mask,
+            coherent: osal.is_coherent(),
+            cache_line: crate::osal::cache_line_size(osal),
+            strict_alignment: false,
+        }
+    }
+
+    /// The default context, using the `Osal` given to [`crate::init`].
+    pub fn global(dma_mask: u64) -> Self {
+        Self::new(get_osal(), dma_mask)
+    }
+
+    pub fn with_dma_mask(mut self, dma_mask: u64) -> Self {
+        self.dma_mask = dma_mask;
+        self
+    }
+
+    /// Override whether this device snoops the cpu caches, the default comes from [`Osal::is_coherent`].
+    pub fn with_coherent(mut self, coherent: bool) -> Self {
+        self.coherent = coherent;
+        self
+    }
+
+    /// Reject device-writable `DSlice`s that share cache lines with other data,
+    /// instead of cleaning the shared lines on every invalidate.
+    pub fn with_strict_alignment(mut self, strict: bool) -> Self {
+        self.strict_alignment = strict;
+        self
+    }
+
+    pub fn osal(&self) -> &'static dyn Osal {
+        self.osal
+    }
+
+    pub fn dma_mask(&self) -> u64 {
+        self.dma_mask
+    }
+
+    pub fn is_coherent(&self) -> bool {
+        self.coherent
+    }
+
+    pub fn cache_line_size(&self) -> usize {
+        self.cache_line
+    }
+
+    pub fn is_strict_alignment(&self) -> bool {
+        self.strict_alignment
+    }
+
+    /// Whether `addr..addr + size` covers whole cache lines only.
+    pub fn is_cache_aligned(&self, addr: NonNull<u8>, size: usize) -> bool {
+        let mask = self.cache_line - 1;
+        (addr.as_ptr() as usize | size) & mask == 0
+    }
+
+    pub(crate) fn map(
+        &self,
+        addr: NonNull<u8>,
+        size: usize,
+        direction: Direction,
+    ) -> Result<u64, DError> {
+        let bus_addr = self.osal.try_map(addr, size, direction, self.dma_mask)?;
+        #[cfg(feature = "dma-debug")]
+        crate::debug::on_map(addr, size, direction);
+        Ok(bus_addr)
+    }
+
+    pub(crate) fn unmap(&self, addr: NonNull<u8>, size: usize) {
+        #[cfg(feature = "dma-debug")]
+        crate::debug::on_unmap(addr, size);
+        self.osal.unmap(addr, size)
+    }
+
+    /// Invalidate the cache lines covering `addr..addr + size`.
+    ///
+    /// The lines at either end may hold cpu writes outside the range, they are
+    /// cleaned before the whole span is invalidated so those writes survive.
+    pub fn invalidate(&self, addr: NonNull<u8>, size: usize) {
+        #[cfg(feature = "dma-debug")]
+        crate::debug::on_sync(addr, size, crate::debug::Owner::Cpu);
+        self.invalidate_lines(addr, size);
+    }
+
+    /// Clean the cache lines covering `addr..addr + size`.
+    pub fn flush(&self, addr: NonNull<u8>, size: usize) {
+        #[cfg(feature = "dma-debug")]
+        crate::debug::on_sync(addr, size, crate::debug::Owner::Device);
+        self.flush_lines(addr, size);
+    }
+
+    /// Clean and invalidate the cache lines covering `addr..addr + size`.
+    pub fn flush_invalidate(&self, addr: NonNull<u8>, size: usize) {
+        #[cfg(feature = "dma-debug")]
+        crate::debug::on_sync(addr, size, crate::debug::Owner::Device);
+        self.flush_invalidate_lines(addr, size);
+    }
+
+    /// The cache operations below skip the `dma-debug` registry, for the
+    /// `sync_*` paths that report themselves and for bounce slots.
+    pub(crate) fn invalidate_lines(&self, addr: NonNull<u8>, size: usize) {
+        if self.coherent || size == 0 {
+            return;
+        }
+        let mask = self.cache_line - 1;
+        let start = addr.as_ptr() as usize;
+        let end = start + size;
+        let first = start & !mask;
+        let last = (end - 1) & !mask;
+
+        if start != first {
+            self.osal.flush(line_ptr(first), self.cache_line);
+        }
+        if end & mask != 0 && (last != first || start == first) {
+            self.osal.flush(line_ptr(last), self.cache_line);
+        }
+        self.osal
+            .invalidate(line_ptr(first), last + self.cache_line - first);
+    }
+
+    pub(crate) fn flush_lines(&self, addr: NonNull<u8>, size: usize) {
+        if self.coherent || size == 0 {
+            return;
+        }
+        let mask = self.cache_line - 1;
+        let start = addr.as_ptr() as usize & !mask;
+        let end = (addr.as_ptr() as usize + size + mask) & !mask;
+        self.osal.flush(line_ptr(start), end - start)
+    }
+
+    pub(crate) fn flush_invalidate_lines(&self, addr: NonNull<u8>, size: usize) {
+        if self.coherent || size == 0 {
+            return;
+        }
+        let mask = self.cache_line - 1;
+        let start = addr.as_ptr() as usize & !mask;
+        let end = (addr.as_ptr() as usize + size + mask) & !mask;
+        self.osal.flush_invalidate(line_ptr(start), end - start)
+    }
+
+    pub fn dma_wmb(&self) {
+        self.osal.dma_wmb()
+    }
+
+    pub fn dma_rmb(&self) {
+        self.osal.dma_rmb()
+    }
+
+    pub fn dma_mb(&self) {
+        self.osal.dma_mb()
+    }
+
+    /// Hand `ptr..ptr + size` to the device, like Linux `dma_sync_*_for_device`.
+    ///
+    /// `ToDevice` cleans so the device reads the cpu writes. `FromDevice` and
+    /// `Bidirectional` clean and invalidate, so no dirty line, e.g. from
+    /// zeroing, is evicted on top of what the device writes. Implies
+    /// `dma_wmb` after, so the data is visible before a later doorbell write.
+    ///
+    /// Cpu writes through the buffer types, their `sync_for_device` and write
+    /// guards all end up here and carry the same barrier.
+    pub fn sync_for_device(&self, direction: Direction, ptr: NonNull<u8>, size: usize) {
+        #[cfg(feature = "dma-debug")]
+        crate::debug::on_sync(ptr, size, crate::debug::Owner::Device);
+        self.clean_for_device(direction, ptr, size);
+    }
+
+    /// The cache maintenance of [`Self::sync_for_device`] without handing the
+    /// memory over, for cpu writes and memory outside any mapping such as a
+    /// bounce slot.
+    pub(crate) fn clean_for_device(&self, direction: Direction, ptr: NonNull<u8>, size: usize) {
+        match direction {
+            Direction::ToDevice => self.flush_lines(ptr, size),
+            Direction::FromDevice | Direction::Bidirectional => {
+                self.flush_invalidate_lines(ptr, size)
+            }
+        }
+        self.dma_wmb();
+    }
+
+    /// Take `ptr..ptr + size` back from the device, like Linux `dma_sync_*_for_cpu`,
+    /// e.g. only the part of a receive buffer the device reported writing.
+    ///
+    /// `FromDevice` and `Bidirectional` invalidate lines speculatively loaded
+    /// while the device owned the buffer, `ToDevice` needs nothing. Implies
+    /// `dma_rmb` before, so nothing is read ahead of an earlier completion check.
+    ///
+    /// Reads through the buffer types, their `sync_for_cpu` and guards all end
+    /// up here and carry the same barrier.
+    pub fn sync_for_cpu(&self, direction: Direction, ptr: NonNull<u8>, size: usize) {
+        #[cfg(feature = "dma-debug")]
+        crate::debug::on_sync(ptr, size, crate::debug::Owner::Cpu);
+        if matches!(direction, Direction::FromDevice | Direction::Bidirectional) {
+            self.dma_rmb();
+            self.invalidate_lines(ptr, size);
+        }
+    }
+
+    pub(crate) fn alloc_coherent(&self, layout: core::alloc::Layout) -> Option<CoherentMem> {
+        unsafe { self.osal.alloc_coherent(self.dma_mask, layout) }
+    }
+
+    pub(crate) fn dealloc_coherent(&self, mem: CoherentMem, layout: core::alloc::Layout) {
+        unsafe { self.osal.dealloc_coherent(mem, layout) }
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        unsafe { self.osal.alloc(self.dma_mask, layout) }
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        unsafe { self.osal.dealloc(ptr, layout) }
+    }
+}
+
+fn line_ptr(addr: usize) -> NonNull<u8> {
+    unsafe { NonNull::new_unchecked(addr as *mut u8) }
+}
+
+impl core::fmt::Debug for DmaDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DmaDevice")
+            .field("dma_mask", &format_args!("{:#x}", self.dma_mask))
+            .field("coherent", &self.coherent)
+            .field("cache_line", &self.cache_line)
+            .field("strict_alignment", &self.strict_alignment)
+            .finish()
+    }
+}
@@ -1,38 +1,68 @@
-use core::alloc::Layout;
+use core::{alloc::Layout, marker::PhantomData};
 
-use crate::{dma::alloc::DError, Direction};
+use crate::{
+    dma::{
+        cpu_wrote,
+        dir::{DeviceReads, DeviceWrites, DmaDirection},
+    },
+    DError, Direction, DmaDevice,
+};
 
 use super::DCommon;
 
-pub struct DBox<T> {
+/// A single dma `T`, directions work like [`crate::DVec`].
+pub struct DBox<T, D = Direction> {
     inner: DCommon<T>,
+    _dir: PhantomData<D>,
 }
 
-impl<T> DBox<T> {
+impl<T, D: DmaDirection> DBox<T, D> {
     const SIZE: usize = core::mem::size_of::<T>();
 
-    pub fn zero_with_align(
-        dma_mask: u64,
-        direction: Direction,
-        align: usize,
-    ) -> Result<Self, super::DError> {
+    pub fn zero_with_align(dma_mask: u64, direction: D, align: usize) -> Result<Self, DError> {
+        Self::zero_with_align_in(&DmaDevice::global(dma_mask), direction, align)
+    }
+
+    pub fn zero_with_align_in(dev: &DmaDevice, direction: D, align: usize) -> Result<Self, DError> {
         let layout = Layout::from_size_align(Self::SIZE, align)?;
 
         Ok(Self {
-            inner: DCommon::zeros(dma_mask, layout, direction)?,
+            inner: DCommon::zeros(dev, layout, direction.direction())?,
+            _dir: PhantomData,
         })
     }
 
-    pub fn zero(dma_mask: u64, direction: Direction) -> Result<Self, DError> {
+    pub fn zero(dma_mask: u64, direction: D) -> Result<Self, DError> {
+        Self::zero_in(&DmaDevice::global(dma_mask), direction)
+    }
+
+    pub fn zero_in(dev: &DmaDevice, direction: D) -> Result<Self, DError> {
         let layout = Layout::new::<T>();
         Ok(Self {
-            inner: DCommon::zeros(dma_mask, layout, direction)?,
+            inner: DCommon::zeros(dev, layout, direction.direction())?,
+            _dir: PhantomData,
         })
     }
     pub fn bus_addr(&self) -> u64 {
         self.inner.bus_addr
     }
 
+    /// [`crate::DmaDevice::sync_for_device`] on the value.
+    pub fn confirm_write_all(&self) {
+        self.inner.confirm_write_all();
+    }
+
+    pub(super) fn common(&self) -> &DCommon<T> {
+        &self.inner
+    }
+}
+
+impl<T, D: DeviceWrites> DBox<T, D> {
+    /// [`crate::DmaDevice::sync_for_cpu`] on the value.
+    pub fn prepare_read_all(&self) {
+        self.inner.prepare_read_all();
+    }
+
     pub fn read(&self) -> T {
         unsafe {
             let ptr = self.inner.addr;
@@ -42,17 +72,22 @@ impl<T> DBox<T> {
             ptr.read_volatile()
         }
     }
+}
 
+impl<T, D: DeviceReads> DBox<T, D> {
     pub fn write(&mut self, value: T) {
         unsafe {
             let ptr = self.inner.addr;
 
             ptr.write_volatile(value);
 
-            self.inner.confirm_write(ptr.cast(), Self::SIZE);
+            cpu_wrote(ptr.cast(), Self::SIZE);
+            self.inner.write_back(ptr.cast(), Self::SIZE);
         }
     }
+}
 
+impl<T, D: DeviceReads + DeviceWrites> DBox<T, D> {
     pub fn modify(&mut self, f: impl FnOnce(&mut T)) {
         unsafe {
             let mut ptr = self.inner.addr;
@@ -61,7 +96,8 @@ impl<T> DBox<T> {
 
             f(ptr.as_mut());
 
-            self.inner.confirm_write(ptr.cast(), Self::SIZE);
+            cpu_wrote(ptr.cast(), Self::SIZE);
+            self.inner.write_back(ptr.cast(), Self::SIZE);
         }
     }
 }
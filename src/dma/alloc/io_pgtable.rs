@@ -0,0 +1,325 @@
+use alloc::collections::BTreeMap;
+use core::ops::BitOr;
+
+use crate::{DError, DVec, Direction, DmaDevice};
+
+const GRANULE: u64 = 0x1000;
+const ENTRIES: usize = 512;
+const IOVA_BITS: u32 = 48;
+/// output address bits [47:12] of a descriptor
+const ADDR_MASK: u64 = ((1 << IOVA_BITS) - 1) & !(GRANULE - 1);
+
+const PTE_VALID: u64 = 1;
+const PTE_TYPE_MASK: u64 = 3;
+const PTE_TYPE_BLOCK: u64 = 1;
+const PTE_TYPE_TABLE: u64 = 3;
+const PTE_TYPE_PAGE: u64 = 3;
+
+const PTE_SH_IS: u64 = 3 << 8;
+const PTE_AF: u64 = 1 << 10;
+const PTE_NG: u64 = 1 << 11;
+const PTE_XN: u64 = 3 << 53;
+
+const PTE_AP_UNPRIV: u64 = 1 << 6;
+const PTE_AP_RDONLY: u64 = 1 << 7;
+const PTE_ATTRINDX_SHIFT: u64 = 2;
+
+const PTE_S2AP_READ: u64 = 1 << 6;
+const PTE_S2AP_WRITE: u64 = 1 << 7;
+const PTE_MEMATTR_DEV: u64 = 0x1 << 2;
+const PTE_MEMATTR_NC: u64 = 0x5 << 2;
+const PTE_MEMATTR_OIWB: u64 = 0xf << 2;
+
+const MAIR_IDX_NC: u64 = 0;
+const MAIR_IDX_CACHE: u64 = 1;
+const MAIR_IDX_DEV: u64 = 2;
+
+/// Translation regime of an [`IoPageTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LpaeStage {
+    /// IOVA to PA, attributes index [`IoPageTable::mair`]
+    Stage1,
+    /// IPA to PA
+    Stage2,
+}
+
+/// Access permissions and memory type of a mapping, combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoProt(u8);
+
+impl IoProt {
+    pub const READ: Self = Self(1);
+    pub const WRITE: Self = Self(1 << 1);
+    /// normal write-back cacheable memory, for walkers and devices that snoop
+    pub const CACHE: Self = Self(1 << 2);
+    pub const NOEXEC: Self = Self(1 << 3);
+    /// device memory, e.g. an MSI doorbell
+    pub const MMIO: Self = Self(1 << 4);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for IoProt {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// ARM LPAE IO page table with a 4K granule and 48 bit input addresses.
+///
+/// Tables are `DVec`s for the device the IOMMU walks them as, addressed by
+/// their bus address, and every descriptor update is written with
+/// `confirm_write` so a non-coherent walker sees it. A next level table is
+/// flushed before the entry pointing to it. Invalidating the IOMMU TLB after
+/// `unmap` is left to the caller. Tables stay allocated until the page table
+/// is dropped.
+pub struct IoPageTable {
+    dev: DmaDevice,
+    stage: LpaeStage,
+    root: u64,
+    tables: BTreeMap<u64, DVec<u64>>,
+}
+
+impl IoPageTable {
+    pub fn new(dev: &DmaDevice, stage: LpaeStage) -> Result<Self, DError> {
+        // descriptors hold 48 bit table addresses
+        let dev = dev.with_dma_mask(dev.dma_mask() & ((1 << IOVA_BITS) - 1));
+        let mut pt = Self {
+            dev,
+            stage,
+            root: 0,
+            tables: BTreeMap::new(),
+        };
+        pt.root = pt.alloc_table()?;
+        Ok(pt)
+    }
+
+    pub fn stage(&self) -> LpaeStage {
+        self.stage
+    }
+
+    /// Bus address of the level 0 table, for TTBR0 or VTTBR.
+    pub fn root_bus_addr(&self) -> u64 {
+        self.root
+    }
+
+    /// MAIR value matching the stage 1 attribute indexes: non-cacheable,
+    /// write-back cacheable and device nGnRE.
+    pub fn mair(&self) -> u64 {
+        0x44 << (8 * MAIR_IDX_NC) | 0xff << (8 * MAIR_IDX_CACHE) | 0x04 << (8 * MAIR_IDX_DEV)
+    }
+
+    /// TCR (stage 1) or VTCR (stage 2) value for this table: 48 bit input,
+    /// 4K granule, walks write-back cacheable and inner shareable. Clear the
+    /// cacheability fields for a walker that does not snoop.
+    pub fn tcr(&self) -> u64 {
+        let t0sz = 64 - IOVA_BITS as u64;
+        let walk = 1 << 8 | 1 << 10 | 3 << 12;
+        match self.stage {
+            LpaeStage::Stage1 => t0sz | walk,
+            // SL0 starts at level 0, PS is 48 bit
+            LpaeStage::Stage2 => t0sz | 2 << 6 | walk | 5 << 16,
+        }
+    }
+
+    /// Map `size` bytes at `iova` to `phys`, using 1G and 2M blocks where
+    /// both addresses are aligned.
+    ///
+    /// Nothing is left mapped if part of the range was already mapped.
+    pub fn map(&mut self, iova: u64, phys: u64, size: usize, prot: IoProt) -> Result<(), DError> {
+        let size64 = size as u64;
+        if !iova.is_multiple_of(GRANULE)
+            || !phys.is_multiple_of(GRANULE)
+            || !size64.is_multiple_of(GRANULE)
+            || size == 0
+            || iova
+                .checked_add(size64)
+                .is_none_or(|end| end > 1 << IOVA_BITS)
+            || phys
+                .checked_add(size64)
+                .is_none_or(|end| end > 1 << IOVA_BITS)
+            || !(prot.contains(IoProt::READ) || prot.contains(IoProt::WRITE))
+        {
+            return Err(DError::InvalidIova { iova, size });
+        }
+
+        let mut off = 0;
+        while off < size64 {
+            let (va, pa, left) = (iova + off, phys + off, size64 - off);
+            let level = (1..=3)
+                .find(|&l| {
+                    let block = level_size(l);
+                    va.is_multiple_of(block) && pa.is_multiple_of(block) && left >= block
+                })
+                .unwrap();
+            if let Err(e) = self.map_one(va, pa, level, prot) {
+                if off > 0 {
+                    let _ = self.unmap(iova, off as usize);
+                }
+                return Err(e);
+            }
+            off += level_size(level);
+        }
+        Ok(())
+    }
+
+    /// Unmap `size` bytes at `iova`, blocks must be unmapped whole.
+    pub fn unmap(&mut self, iova: u64, size: usize) -> Result<(), DError> {
+        let size64 = size as u64;
+        if !iova.is_multiple_of(GRANULE) || !size64.is_multiple_of(GRANULE) {
+            return Err(DError::InvalidIova { iova, size });
+        }
+
+        let mut off = 0;
+        while off < size64 {
+            let va = iova + off;
+            let (table, level, _) = self
+                .walk(va)
+                .ok_or(DError::InvalidIova { iova: va, size })?;
+            let block = level_size(level);
+            if !va.is_multiple_of(block) || size64 - off < block {
+                return Err(DError::InvalidIova { iova: va, size });
+            }
+            self.tables
+                .get_mut(&table)
+                .unwrap()
+                .set(index(va, level), 0);
+            off += block;
+        }
+        Ok(())
+    }
+
+    pub fn iova_to_phys(&self, iova: u64) -> Option<u64> {
+        let (_, level, pte) = self.walk(iova)?;
+        let block = level_size(level);
+        Some((pte & ADDR_MASK & !(block - 1)) | (iova & (block - 1)))
+    }
+
+    /// The table holding the leaf for `iova`, its level and the leaf descriptor.
+    fn walk(&self, iova: u64) -> Option<(u64, usize, u64)> {
+        if iova >> IOVA_BITS != 0 {
+            return None;
+        }
+        let mut table = self.root;
+        for level in 0..=3 {
+            let pte = self.tables[&table].get(index(iova, level))?;
+            if pte & PTE_VALID == 0 {
+                return None;
+            }
+            if level == 3 || pte & PTE_TYPE_MASK == PTE_TYPE_BLOCK {
+                return Some((table, level, pte));
+            }
+            table = pte & ADDR_MASK;
+        }
+        None
+    }
+
+    fn map_one(&mut self, iova: u64, phys: u64, level: usize, prot: IoProt) -> Result<(), DError> {
+        let err = DError::InvalidIova {
+            iova,
+            size: level_size(level) as usize,
+        };
+        let mut table = self.root;
+        for l in 0..level {
+            let i = index(iova, l);
+            let pte = self.tables[&table].get(i).unwrap();
+            table = match pte & PTE_TYPE_MASK {
+                PTE_TYPE_TABLE => pte & ADDR_MASK,
+                0 => {
+                    let next = self.alloc_table()?;
+                    self.tables
+                        .get_mut(&table)
+                        .unwrap()
+                        .set(i, next | PTE_TYPE_TABLE);
+                    next
+                }
+                // a block already covers it
+                _ => return Err(err),
+            };
+        }
+
+        let i = index(iova, level);
+        let t = self.tables.get_mut(&table).unwrap();
+        if t.get(i).unwrap() & PTE_VALID != 0 {
+            return Err(err);
+        }
+        let kind = if level == 3 {
+            PTE_TYPE_PAGE
+        } else {
+            PTE_TYPE_BLOCK
+        };
+        t.set(i, phys | self.stage.attrs(prot) | kind);
+        Ok(())
+    }
+
+    fn alloc_table(&mut self) -> Result<u64, DError> {
+        let table: DVec<u64> =
+            DVec::zeros_in(&self.dev, ENTRIES, GRANULE as usize, Direction::ToDevice)?;
+        let bus_addr = table.bus_addr();
+        if bus_addr & !ADDR_MASK != 0 {
+            return Err(DError::DmaMaskNotMatch {
+                mask: ADDR_MASK,
+                got: bus_addr,
+            });
+        }
+        self.tables.insert(bus_addr, table);
+        Ok(bus_addr)
+    }
+}
+
+impl LpaeStage {
+    /// Leaf descriptor bits for `prot`, without the output address and type.
+    fn attrs(self, prot: IoProt) -> u64 {
+        let mut pte = PTE_AF;
+        if !prot.contains(IoProt::MMIO) {
+            pte |= PTE_SH_IS;
+        }
+        if prot.contains(IoProt::NOEXEC) {
+            pte |= PTE_XN;
+        }
+        match self {
+            LpaeStage::Stage1 => {
+                pte |= PTE_NG | PTE_AP_UNPRIV;
+                if !prot.contains(IoProt::WRITE) {
+                    pte |= PTE_AP_RDONLY;
+                }
+                let idx = if prot.contains(IoProt::MMIO) {
+                    MAIR_IDX_DEV
+                } else if prot.contains(IoProt::CACHE) {
+                    MAIR_IDX_CACHE
+                } else {
+                    MAIR_IDX_NC
+                };
+                pte | idx << PTE_ATTRINDX_SHIFT
+            }
+            LpaeStage::Stage2 => {
+                if prot.contains(IoProt::READ) {
+                    pte |= PTE_S2AP_READ;
+                }
+                if prot.contains(IoProt::WRITE) {
+                    pte |= PTE_S2AP_WRITE;
+                }
+                pte | if prot.contains(IoProt::MMIO) {
+                    PTE_MEMATTR_DEV
+                } else if prot.contains(IoProt::CACHE) {
+                    PTE_MEMATTR_OIWB
+                } else {
+                    PTE_MEMATTR_NC
+                }
+            }
+        }
+    }
+}
+
+/// Bytes one entry at `level` maps, 512G at level 0 down to 4K at level 3.
+fn level_size(level: usize) -> u64 {
+    GRANULE << (9 * (3 - level))
+}
+
+fn index(iova: u64, level: usize) -> usize {
+    ((iova >> (12 + 9 * (3 - level))) as usize) & (ENTRIES - 1)
+}
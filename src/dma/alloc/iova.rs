@@ -0,0 +1,219 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::DError;
+
+/// Allocations up to `1 << CACHED_ORDERS - 1` granules are rounded to a power
+/// of two, size aligned and cached when freed.
+const CACHED_ORDERS: usize = 6;
+/// Freed ranges kept per cached size class.
+const CACHE_DEPTH: usize = 32;
+
+/// Bus address space allocator for `Osal` implementations behind an IOMMU.
+///
+/// Hands out granule aligned ranges of the aperture top down, below the dma
+/// mask of the request, which `Osal::try_map` gets from the mapping device.
+/// Recently freed small ranges are cached per size class and reused without
+/// touching the free tree.
+pub struct IovaAllocator {
+    granule: u64,
+    aperture: Range<u64>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    granule: u64,
+    /// free ranges, start to end
+    free: BTreeMap<u64, u64>,
+    cache: [Vec<u64>; CACHED_ORDERS],
+}
+
+impl IovaAllocator {
+    /// `granule` is the IOMMU page size, a power of two the aperture is aligned to.
+    pub fn new(aperture: Range<u64>, granule: u64) -> Self {
+        assert!(granule.is_power_of_two());
+        assert!(
+            aperture.start.is_multiple_of(granule) && aperture.end.is_multiple_of(granule),
+            "aperture {aperture:#x?} is not aligned to {granule:#x}"
+        );
+        let mut free = BTreeMap::new();
+        if aperture.start < aperture.end {
+            free.insert(aperture.start, aperture.end);
+        }
+        Self {
+            granule,
+            aperture,
+            inner: Mutex::new(Inner {
+                granule,
+                free,
+                cache: Default::default(),
+            }),
+        }
+    }
+
+    pub fn aperture(&self) -> Range<u64> {
+        self.aperture.clone()
+    }
+
+    pub fn granule(&self) -> u64 {
+        self.granule
+    }
+
+    /// Allocate `size` bytes of bus address space ending at or below `dma_mask`.
+    pub fn alloc(&self, size: usize, dma_mask: u64) -> Result<u64, DError> {
+        let len = self.len_of(size);
+        let align = if self.order_of(len).is_some() {
+            len
+        } else {
+            self.granule
+        };
+        self.alloc_range(len, align, dma_mask)
+    }
+
+    /// Like [`Self::alloc`], aligned to `size` rounded up to a power of two,
+    /// for devices and page tables that need naturally aligned ranges.
+    pub fn alloc_aligned(&self, size: usize, dma_mask: u64) -> Result<u64, DError> {
+        let len = self.len_of(size);
+        self.alloc_range(len, len.next_power_of_two(), dma_mask)
+    }
+
+    /// Give back a range from `alloc` or `alloc_aligned` with the same `size`.
+    pub fn free(&self, iova: u64, size: usize) {
+        let len = self.len_of(size);
+        assert!(
+            iova >= self.aperture.start && iova + len <= self.aperture.end,
+            "{iova:#x} size {size:#x} is outside the aperture"
+        );
+        let mut inner = self.inner.lock();
+        // `insert` checks the tree itself, the full scan also covers the caches
+        #[cfg(any(debug_assertions, feature = "dma-debug"))]
+        inner.assert_allocated(iova, iova + len);
+        if let Some(order) = self.order_of(len) {
+            if inner.cache[order].len() < CACHE_DEPTH {
+                inner.cache[order].push(iova);
+                return;
+            }
+        }
+        inner.insert(iova, iova + len);
+    }
+
+    /// Move all cached ranges back to the free tree, e.g. before a large allocation.
+    pub fn flush_cache(&self) {
+        self.inner.lock().flush_cache();
+    }
+
+    /// Bytes of the aperture not allocated, cached ranges count as free.
+    pub fn free_size(&self) -> u64 {
+        let inner = self.inner.lock();
+        let cached: u64 = inner
+            .cache
+            .iter()
+            .enumerate()
+            .map(|(order, c)| c.len() as u64 * (self.granule << order))
+            .sum();
+        inner.free.iter().map(|(s, e)| e - s).sum::<u64>() + cached
+    }
+
+    /// `size` in bytes rounded to whole granules, and to a power of two for cached sizes.
+    fn len_of(&self, size: usize) -> u64 {
+        let granules = (size as u64).div_ceil(self.granule).max(1);
+        if granules <= 1 << (CACHED_ORDERS - 1) {
+            granules.next_power_of_two() * self.granule
+        } else {
+            granules * self.granule
+        }
+    }
+
+    fn order_of(&self, len: u64) -> Option<usize> {
+        let order = (len / self.granule).trailing_zeros() as usize;
+        (len == self.granule << order && order < CACHED_ORDERS).then_some(order)
+    }
+
+    fn alloc_range(&self, len: u64, align: u64, dma_mask: u64) -> Result<u64, DError> {
+        let limit = self.aperture.end.min(dma_mask.saturating_add(1));
+        let mut inner = self.inner.lock();
+
+        if let Some(order) = self.order_of(len) {
+            let cache = &mut inner.cache[order];
+            if let Some(i) = cache
+                .iter()
+                .rposition(|&iova| iova + len <= limit && iova.is_multiple_of(align))
+            {
+                return Ok(cache.swap_remove(i));
+            }
+        }
+
+        if let Some(iova) = inner.take(len, align, limit) {
+            return Ok(iova);
+        }
+        // cached ranges may merge into one that fits
+        inner.flush_cache();
+        inner.take(len, align, limit).ok_or(DError::IommuExhausted)
+    }
+}
+
+impl Inner {
+    /// Carve `len` bytes aligned to `align` out of the highest free range below `limit`.
+    fn take(&mut self, len: u64, align: u64, limit: u64) -> Option<u64> {
+        let (start, end, iova) = self.free.range(..limit).rev().find_map(|(&start, &end)| {
+            let top = end.min(limit).checked_sub(len)?;
+            let iova = top & !(align - 1);
+            (iova >= start).then_some((start, end, iova))
+        })?;
+
+        self.free.remove(&start);
+        if start < iova {
+            self.free.insert(start, iova);
+        }
+        if iova + len < end {
+            self.free.insert(iova + len, end);
+        }
+        Some(iova)
+    }
+
+    /// Add `start..end` to the free tree, merging with its neighbours.
+    fn insert(&mut self, mut start: u64, mut end: u64) {
+        if let Some((&prev, &prev_end)) = self.free.range(..start).next_back() {
+            assert!(prev_end <= start, "double free of {start:#x}");
+            if prev_end == start {
+                self.free.remove(&prev);
+                start = prev;
+            }
+        }
+        if let Some(next_end) = self.free.get(&end).copied() {
+            self.free.remove(&end);
+            end = next_end;
+        }
+        assert!(
+            self.free.range(start..end).next().is_none(),
+            "double free of {start:#x}"
+        );
+        self.free.insert(start, end);
+    }
+
+    /// Panics if any of `start..end` is free already, in the tree or a cache.
+    #[cfg(any(debug_assertions, feature = "dma-debug"))]
+    fn assert_allocated(&self, start: u64, end: u64) {
+        let in_tree = self
+            .free
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, &free_end)| free_end > start);
+        let in_cache = self.cache.iter().enumerate().any(|(order, cache)| {
+            let len = self.granule << order;
+            cache.iter().any(|&iova| iova < end && start < iova + len)
+        });
+        assert!(!in_tree && !in_cache, "double free of {start:#x}");
+    }
+
+    fn flush_cache(&mut self) {
+        for order in 0..CACHED_ORDERS {
+            let len = self.granule << order;
+            for iova in core::mem::take(&mut self.cache[order]) {
+                self.insert(iova, iova + len);
+            }
+        }
+    }
+}
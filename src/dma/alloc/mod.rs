@@ -1,73 +1,86 @@
 use alloc::vec::Vec;
 use core::{
     alloc::Layout,
+    ops::{Range, RangeBounds},
     ptr::{slice_from_raw_parts_mut, NonNull},
 };
 
-use crate::{flush, map, unmap, Direction};
+use crate::{
+    dma::{bounce::Bounce, bounce_synced, elem_range, guard::RangeSync, map_with_mask},
+    DError, Direction, DmaDevice,
+};
 
 pub mod r#box;
+pub mod io_pgtable;
+pub mod iova;
 pub mod pool;
+pub mod ring;
+pub mod sg;
+pub mod size_class;
+pub mod transfer;
 pub mod vec;
 
-#[derive(thiserror::Error, Debug, Clone)]
-pub enum DError {
-    #[error("DMA mask not match, required {mask:#x}, got {got:#x}")]
-    DmaMaskNotMatch { mask: u64, got: u64 },
-    #[error("No memory")]
-    NoMemory,
-    #[error("Layout error")]
-    LayoutError,
-}
-
-impl From<core::alloc::LayoutError> for DError {
-    fn from(_: core::alloc::LayoutError) -> Self {
-        DError::LayoutError
-    }
-}
-
 struct DCommon<T> {
+    dev: DmaDevice,
     addr: NonNull<T>,
     bus_addr: u64,
+    /// bytes in use, `layout` may be padded to whole cache lines
+    size: usize,
     layout: Layout,
     direction: Direction,
+    bounce: Option<Bounce>,
+    /// allocated by a `Vec` rather than [`crate::Osal::alloc`]
+    from_vec: bool,
 }
 
 unsafe impl<T: Send> Send for DCommon<T> {}
 
 impl<T> DCommon<T> {
-    pub fn zeros(dma_mask: u64, layout: Layout, direction: Direction) -> Result<Self, DError> {
+    /// The allocation is aligned and padded to whole cache lines, so syncing it
+    /// never touches lines shared with other data.
+    pub fn zeros(dev: &DmaDevice, layout: Layout, direction: Direction) -> Result<Self, DError> {
+        let size = layout.size();
+        let layout = layout.align_to(dev.cache_line_size())?.pad_to_align();
+        if layout.size() == 0 {
+            // nothing to allocate or map, `Drop` skips empty buffers as well
+            return Ok(Self {
+                dev: *dev,
+                bus_addr: 0,
+                addr: NonNull::dangling(),
+                size,
+                layout,
+                direction,
+                bounce: None,
+                from_vec: false,
+            });
+        }
         unsafe {
-            let mut addr = NonNull::new(crate::alloc(dma_mask, layout)).ok_or(DError::NoMemory)?;
+            let mut addr = NonNull::new(dev.alloc(layout)).ok_or(DError::NoMemory)?;
             (*slice_from_raw_parts_mut(addr.as_mut(), layout.size())).fill(0);
 
-            let bus_addr = map(addr, layout.size(), direction);
-            if let Err(e) = Self::check_dma_mask(dma_mask, bus_addr) {
-                crate::dealloc(addr.as_ptr() as _, layout);
-                return Err(e);
-            }
-            flush(addr, layout.size());
+            let (bus_addr, bounce) =
+                match map_with_mask(dev, addr, layout.size(), layout.align(), direction, true) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        dev.dealloc(addr.as_ptr() as _, layout);
+                        return Err(e);
+                    }
+                };
             Ok(Self {
+                dev: *dev,
                 bus_addr,
                 addr: addr.cast(),
+                size,
                 layout,
                 direction,
+                bounce,
+                from_vec: false,
             })
         }
     }
 
-    fn check_dma_mask(dma_mask: u64, bus_addr: u64) -> Result<(), DError> {
-        if (bus_addr) & (dma_mask) != (bus_addr) {
-            return Err(DError::DmaMaskNotMatch {
-                mask: dma_mask,
-                got: bus_addr,
-            });
-        }
-        Ok(())
-    }
-
     pub fn from_vec(
-        dma_mask: u64,
+        dev: &DmaDevice,
         mut value: Vec<T>,
         direction: Direction,
     ) -> Result<Self, DError> {
@@ -79,41 +92,134 @@ impl<T> DCommon<T> {
 
             let addr = NonNull::new(value.as_mut_ptr()).unwrap();
 
-            let bus_addr = map(addr.cast(), layout.size(), direction);
-            Self::check_dma_mask(dma_mask, bus_addr)?;
+            let (bus_addr, bounce) = map_with_mask(
+                dev,
+                addr.cast(),
+                layout.size(),
+                layout.align(),
+                direction,
+                true,
+            )?;
 
+            let size = value.len() * size_of::<T>();
             core::mem::forget(value);
-
-            flush(addr.cast(), layout.size());
             Ok(Self {
+                dev: *dev,
                 bus_addr,
                 addr: addr.cast(),
+                size,
                 layout,
                 direction,
+                bounce,
+                from_vec: true,
             })
         }
     }
 
     pub fn prepare_read(&self, ptr: NonNull<u8>, size: usize) {
-        self.direction.prepare_read(ptr, size);
+        match &self.bounce {
+            Some(bounce) => {
+                bounce_synced(ptr, size, false);
+                bounce.prepare_read(self.direction, ptr, size)
+            }
+            None => self.dev.sync_for_cpu(self.direction, ptr, size),
+        }
     }
 
     pub fn confirm_write(&self, ptr: NonNull<u8>, size: usize) {
-        self.direction.confirm_write(ptr, size);
+        match &self.bounce {
+            Some(bounce) => {
+                bounce_synced(ptr, size, true);
+                bounce.confirm_write(self.direction, ptr, size)
+            }
+            None => self.dev.sync_for_device(self.direction, ptr, size),
+        }
+    }
+
+    /// Make cpu writes visible to the device while the cpu keeps the buffer.
+    pub fn write_back(&self, ptr: NonNull<u8>, size: usize) {
+        match &self.bounce {
+            Some(bounce) => bounce.confirm_write(self.direction, ptr, size),
+            None => self.dev.clean_for_device(self.direction, ptr, size),
+        }
     }
 
     pub fn confirm_write_all(&self) {
-        self.direction
-            .confirm_write(self.addr.cast(), self.layout.size());
+        self.confirm_write(self.addr.cast(), self.layout.size());
+    }
+
+    pub fn prepare_read_all(&self) {
+        self.prepare_read(self.addr.cast(), self.layout.size());
+    }
+
+    pub fn prepare_read_range(&self, range: impl RangeBounds<usize>) {
+        let range = elem_range(range, self.size / size_of::<T>());
+        let ptr = unsafe { self.addr.add(range.start) };
+        self.prepare_read(ptr.cast(), range.len() * size_of::<T>());
+    }
+
+    pub fn confirm_write_range(&self, range: impl RangeBounds<usize>) {
+        let range = elem_range(range, self.size / size_of::<T>());
+        let ptr = unsafe { self.addr.add(range.start) };
+        self.confirm_write(ptr.cast(), range.len() * size_of::<T>());
+    }
+
+    pub fn write_back_range(&self, range: impl RangeBounds<usize>) {
+        let range = elem_range(range, self.size / size_of::<T>());
+        let ptr = unsafe { self.addr.add(range.start) };
+        self.write_back(ptr.cast(), range.len() * size_of::<T>());
+    }
+
+    /// Move into a new allocation of at least `capacity` bytes, keeping the
+    /// bytes in use. The new buffer is mapped and checked against the dma mask
+    /// before the old one is released.
+    pub fn grow(&mut self, capacity: usize) -> Result<(), DError> {
+        let layout = Layout::from_size_align(capacity, self.layout.align())?;
+        let mut new = DCommon::<T>::zeros(&self.dev, layout, self.direction)?;
+
+        self.prepare_read(self.addr.cast(), self.size);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.addr.as_ptr().cast::<u8>(),
+                new.addr.as_ptr().cast::<u8>(),
+                self.size,
+            );
+        }
+        new.size = self.size;
+        new.write_back(new.addr.cast(), new.size);
+
+        core::mem::swap(self, &mut new);
+        Ok(())
+    }
+
+    /// Undo the mapping, a bounced buffer was already unmapped when it fell back.
+    fn unmap(&self) {
+        if self.bounce.is_none() {
+            self.dev.unmap(self.addr.cast(), self.layout.size());
+        }
+    }
+}
+
+impl<T> RangeSync for DCommon<T> {
+    fn prepare_read_range(&self, range: Range<usize>) {
+        DCommon::prepare_read_range(self, range);
+    }
+
+    fn write_back_range(&self, range: Range<usize>) {
+        DCommon::write_back_range(self, range);
     }
 }
 
 impl<T> Drop for DCommon<T> {
     fn drop(&mut self) {
         if self.layout.size() > 0 {
-            unmap(self.addr.cast(), self.layout.size());
+            self.unmap();
 
-            crate::dealloc(self.addr.as_ptr() as _, self.layout);
+            if self.from_vec {
+                unsafe { alloc::alloc::dealloc(self.addr.as_ptr() as _, self.layout) };
+            } else {
+                self.dev.dealloc(self.addr.as_ptr() as _, self.layout);
+            }
         }
     }
 }
@@ -1,12 +1,20 @@
-use core::ops::{Deref, DerefMut};
+use core::{
+    future::Future,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
 
 use alloc::{
     collections::VecDeque,
     sync::{Arc, Weak},
+    vec::Vec,
 };
 use spin::Mutex;
 
-use crate::{DVec, Direction};
+use crate::{DError, DVec, Direction, DmaDevice};
 
 #[derive(Debug, Clone)]
 pub struct DVecConfig {
@@ -16,14 +24,74 @@ pub struct DVecConfig {
     pub direction: Direction,
 }
 
+/// What [`DVecPool::alloc`] does once `max_outstanding` buffers are handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExhaustPolicy {
+    /// return [`DError::PoolExhausted`]
+    Fail,
+    /// spin until a buffer comes back, `Some(n)` gives up with
+    /// [`DError::PoolExhausted`] after `n` tries
+    Spin(Option<usize>),
+    /// allocate past `max_outstanding` until `hard_cap` buffers are out, then fail
+    Grow { hard_cap: usize },
+}
+
+/// Bounds on a [`DVecPool`], the default keeps the old unbounded behaviour.
+#[derive(Debug, Clone)]
+pub struct DVecPoolLimits {
+    /// buffers handed out at once, idle buffers are only refilled up to this total
+    pub max_outstanding: usize,
+    pub policy: ExhaustPolicy,
+    /// refill idle buffers up to this many when an alloc leaves fewer
+    pub low_watermark: usize,
+    /// [`DVecPool::shrink`] frees idle buffers above this many
+    pub high_watermark: usize,
+}
+
+impl Default for DVecPoolLimits {
+    fn default() -> Self {
+        Self {
+            max_outstanding: usize::MAX,
+            policy: ExhaustPolicy::Fail,
+            low_watermark: 0,
+            high_watermark: usize::MAX,
+        }
+    }
+}
+
+/// Per-cpu or per-queue caches in front of the shared pool.
+///
+/// Magazines are not lock-free, each is a spin lock normally only taken by its
+/// own cpu or queue. `alloc` and `DBuff` drop only `try_lock` it and fall back
+/// to the shared pool when it is held, e.g. after a task migrated. They stay
+/// off the shared lock until a magazine runs empty or full, then half a
+/// magazine moves at once.
+///
+/// Per-cpu magazines need [`crate::Osal::cpu_id`], whose default puts every
+/// cpu on the first magazine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DVecMagazines {
+    /// magazines, picked by [`crate::Osal::cpu_id`] or the queue passed to
+    /// [`DVecPool::alloc_on`], modulo `count`; 0 disables them
+    pub count: usize,
+    /// buffers one magazine holds
+    pub size: usize,
+}
+
 #[derive(Clone)]
 pub struct DVecPool {
-    inner: Arc<Mutex<Inner>>,
+    inner: Arc<Shared>,
 }
 
+/// A buffer from a [`DVecPool`], going back to it when dropped.
+///
+/// It may grow with `push` or `reserve`, a grown buffer is freed on drop
+/// instead of being pooled, so the pool only hands out `config.size` buffers.
 pub struct DBuff {
     data: Option<DVec<u8>>,
-    pool: Weak<Mutex<Inner>>,
+    pool: Weak<Shared>,
+    /// queue given to `alloc_on`, the buffer goes back to its magazine
+    queue: Option<usize>,
 }
 
 unsafe impl Send for DBuff {}
@@ -46,61 +114,456 @@ impl Drop for DBuff {
     fn drop(&mut self) {
         if let Some(data) = self.data.take() {
             if let Some(pool) = self.pool.upgrade() {
-                let mut inner = pool.lock();
-                inner.dealloc(data);
+                pool.dealloc(data, self.queue);
             }
         }
     }
 }
 
-struct Inner {
+struct Shared {
+    dev: DmaDevice,
     config: DVecConfig,
+    /// capacity of a buffer allocated for `config`, larger ones were grown by their user
+    capacity: usize,
+    depot: Mutex<Depot>,
+    magazines: Vec<Mutex<Vec<DVec<u8>>>>,
+    magazine_size: usize,
+    outstanding: AtomicUsize,
+    /// outstanding buffers the limits allow
+    cap: AtomicUsize,
+    /// `Depot::limits.low_watermark`, lets allocs skip the refill check when unset
+    low_watermark: AtomicUsize,
+    /// tries of `ExhaustPolicy::Spin`, 0 for the other policies and
+    /// `usize::MAX` for no limit, read without the depot lock while spinning
+    spins: AtomicUsize,
+    /// length of `Depot::waiters`, lets returns skip the depot when nobody waits
+    waiting: AtomicUsize,
+}
+
+struct Depot {
+    limits: DVecPoolLimits,
     pool: VecDeque<DVec<u8>>,
+    /// [`DAlloc`] futures parked on an exhausted pool, oldest first. The waker
+    /// is taken when woken, the entry keeps its place until the future gets a
+    /// buffer or is dropped.
+    waiters: VecDeque<(u64, Option<Waker>)>,
+    next_waiter: u64,
+}
+
+enum Slot {
+    Idle(DVec<u8>),
+    /// counted as outstanding, the caller allocates it
+    Fresh,
+    Exhausted,
 }
 
-impl Inner {
-    fn alloc(&mut self) -> Option<DVec<u8>> {
-        self.pool.pop_front()
+impl Shared {
+    fn magazine(&self, queue: Option<usize>) -> Option<&Mutex<Vec<DVec<u8>>>> {
+        if self.magazines.is_empty() {
+            return None;
+        }
+        let index = queue.unwrap_or_else(|| self.dev.osal().cpu_id());
+        Some(&self.magazines[index % self.magazines.len()])
+    }
+
+    /// Buffers moved between a magazine and the depot at once.
+    fn batch(&self) -> usize {
+        (self.magazine_size / 2).max(1)
+    }
+
+    fn alloc(&self, queue: Option<usize>) -> Slot {
+        let cap = self.cap.load(Ordering::Relaxed);
+        let reserved = self
+            .outstanding
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < cap).then_some(n + 1)
+            });
+        if reserved.is_err() {
+            return Slot::Exhausted;
+        }
+
+        // a contended magazine means the task migrated, use the depot instead
+        if let Some(mut magazine) = self.magazine(queue).and_then(|m| m.try_lock()) {
+            if magazine.is_empty() {
+                let mut depot = self.depot.lock();
+                let n = self.batch().min(depot.pool.len());
+                magazine.extend(depot.pool.drain(..n));
+            }
+            if let Some(dvec) = magazine.pop() {
+                return Slot::Idle(dvec);
+            }
+        }
+        match self.depot.lock().pool.pop_front() {
+            Some(dvec) => Slot::Idle(dvec),
+            None => Slot::Fresh,
+        }
+    }
+
+    fn dealloc(&self, mut dvec: DVec<u8>, queue: Option<usize>) {
+        if dvec.capacity() > self.capacity {
+            drop(dvec);
+            self.cancel();
+            return;
+        }
+        // undo any truncate by the last user
+        dvec.set_len(self.config.size);
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
+
+        // paired with the recheck in `DAlloc::poll`, either it sees the
+        // slot freed above or this sees its waiter
+        if self.waiting.load(Ordering::SeqCst) == 0 {
+            if let Some(mut magazine) = self.magazine(queue).and_then(|m| m.try_lock()) {
+                if magazine.len() >= self.magazine_size {
+                    let n = self.batch().min(magazine.len());
+                    let start = magazine.len() - n;
+                    self.depot.lock().pool.extend(magazine.drain(start..));
+                }
+                magazine.push(dvec);
+                return;
+            }
+        }
+
+        let waker = {
+            let mut depot = self.depot.lock();
+            depot.pool.push_back(dvec);
+            self.take_waker(&mut depot)
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Give back a slot without returning a buffer, e.g. a [`Slot::Fresh`] that
+    /// could not be allocated.
+    fn cancel(&self) {
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
+        if self.waiting.load(Ordering::SeqCst) > 0 {
+            if let Some(waker) = self.take_waker(&mut self.depot.lock()) {
+                waker.wake();
+            }
+        }
+    }
+
+    fn push_waiter(&self, depot: &mut Depot, waker: Waker) -> u64 {
+        let id = depot.next_waiter;
+        depot.next_waiter += 1;
+        depot.waiters.push_back((id, Some(waker)));
+        self.waiting.store(depot.waiters.len(), Ordering::SeqCst);
+        id
+    }
+
+    /// Wait again in the place of `id`, after being woken without getting a buffer.
+    fn rearm_waiter(&self, depot: &mut Depot, id: u64, waker: &Waker) {
+        if let Some((_, slot)) = depot.waiters.iter_mut().find(|(w, _)| *w == id) {
+            match slot {
+                Some(old) => old.clone_from(waker),
+                None => *slot = Some(waker.clone()),
+            }
+        }
+    }
+
+    fn is_woken(&self, depot: &Depot, id: u64) -> bool {
+        depot
+            .waiters
+            .iter()
+            .any(|(w, waker)| *w == id && waker.is_none())
+    }
+
+    /// Waker of the oldest waiter not woken yet.
+    fn take_waker(&self, depot: &mut Depot) -> Option<Waker> {
+        depot.waiters.iter_mut().find_map(|(_, waker)| waker.take())
+    }
+
+    /// Returns true if the waiter was woken already.
+    fn remove_waiter(&self, depot: &mut Depot, id: u64) -> bool {
+        let Some(i) = depot.waiters.iter().position(|(w, _)| *w == id) else {
+            return false;
+        };
+        let (_, waker) = depot.waiters.remove(i).unwrap();
+        self.waiting.store(depot.waiters.len(), Ordering::SeqCst);
+        waker.is_none()
+    }
+
+    /// Idle depot buffers to allocate to get back to the low watermark.
+    fn refill_count(&self, depot: &Depot) -> usize {
+        let room = depot
+            .limits
+            .max_outstanding
+            .saturating_sub(self.outstanding.load(Ordering::Relaxed) + depot.pool.len());
+        depot
+            .limits
+            .low_watermark
+            .saturating_sub(depot.pool.len())
+            .min(room)
     }
 
-    fn dealloc(&mut self, dvec: DVec<u8>) {
-        self.pool.push_back(dvec);
+    fn alloc_fresh(&self) -> Result<DVec<u8>, DError> {
+        let config = &self.config;
+        DVec::zeros_in(&self.dev, config.size, config.align, config.direction)
+            .inspect_err(|_| self.cancel())
     }
 }
 
 impl DVecPool {
     pub fn new_pool(config: DVecConfig, cap: usize) -> DVecPool {
+        Self::new_pool_in(&DmaDevice::global(u64::MAX), config, cap)
+    }
+
+    /// Buffers are allocated for `dev`, limited by both its dma mask and `config.dma_mask`.
+    pub fn new_pool_in(dev: &DmaDevice, config: DVecConfig, cap: usize) -> DVecPool {
+        Self::new_pool_cached_in(dev, config, cap, DVecMagazines::default())
+    }
+
+    pub fn new_pool_cached(config: DVecConfig, cap: usize, magazines: DVecMagazines) -> DVecPool {
+        Self::new_pool_cached_in(&DmaDevice::global(u64::MAX), config, cap, magazines)
+    }
+
+    /// Like [`Self::new_pool_in`], with per-cpu or per-queue magazines in front of the shared pool.
+    pub fn new_pool_cached_in(
+        dev: &DmaDevice,
+        config: DVecConfig,
+        cap: usize,
+        magazines: DVecMagazines,
+    ) -> DVecPool {
+        let dev = dev.with_dma_mask(dev.dma_mask() & config.dma_mask);
         let mut pool = VecDeque::with_capacity(cap);
         for _ in 0..cap {
-            if let Ok(dvec) =
-                DVec::zeros(config.dma_mask, config.size, config.align, config.direction)
-            {
+            if let Ok(dvec) = DVec::zeros_in(&dev, config.size, config.align, config.direction) {
                 pool.push_back(dvec);
             }
         }
 
+        let capacity = config
+            .size
+            .next_multiple_of(config.align.max(dev.cache_line_size()));
         DVecPool {
-            inner: Arc::new(Mutex::new(Inner { pool, config })),
+            inner: Arc::new(Shared {
+                dev,
+                config,
+                capacity,
+                depot: Mutex::new(Depot {
+                    limits: DVecPoolLimits::default(),
+                    pool,
+                    waiters: VecDeque::new(),
+                    next_waiter: 0,
+                }),
+                magazines: (0..magazines.count)
+                    .map(|_| Mutex::new(Vec::with_capacity(magazines.size)))
+                    .collect(),
+                magazine_size: magazines.size,
+                outstanding: AtomicUsize::new(0),
+                cap: AtomicUsize::new(usize::MAX),
+                low_watermark: AtomicUsize::new(0),
+                spins: AtomicUsize::new(0),
+                waiting: AtomicUsize::new(0),
+            }),
         }
     }
 
-    pub fn alloc(&self) -> Result<DBuff, crate::dma::alloc::DError> {
-        let config = {
-            let mut inner = self.inner.lock();
-            if let Some(dvec) = inner.alloc() {
-                return Ok(DBuff {
-                    data: Some(dvec),
-                    pool: Arc::downgrade(&self.inner),
-                });
-            } else {
-                inner.config.clone()
+    /// Replace the limits, they apply from the next `alloc` on.
+    pub fn set_limits(&self, limits: DVecPoolLimits) {
+        let cap = match limits.policy {
+            ExhaustPolicy::Grow { hard_cap } => hard_cap.max(limits.max_outstanding),
+            _ => limits.max_outstanding,
+        };
+        let spins = match limits.policy {
+            ExhaustPolicy::Spin(max) => max.unwrap_or(usize::MAX),
+            _ => 0,
+        };
+        let mut depot = self.inner.depot.lock();
+        self.inner.cap.store(cap, Ordering::Relaxed);
+        self.inner.spins.store(spins, Ordering::Relaxed);
+        self.inner
+            .low_watermark
+            .store(limits.low_watermark, Ordering::Relaxed);
+        depot.limits = limits;
+    }
+
+    /// Buffers currently handed out.
+    pub fn outstanding(&self) -> usize {
+        self.inner.outstanding.load(Ordering::SeqCst)
+    }
+
+    /// Buffers waiting in the pool, including the magazines.
+    pub fn idle(&self) -> usize {
+        let cached: usize = self.inner.magazines.iter().map(|m| m.lock().len()).sum();
+        self.inner.depot.lock().pool.len() + cached
+    }
+
+    /// Take a buffer, allocating one if the pool is empty.
+    ///
+    /// Once `max_outstanding` buffers are out the [`ExhaustPolicy`] decides
+    /// between failing with [`DError::PoolExhausted`], spinning and growing.
+    pub fn alloc(&self) -> Result<DBuff, DError> {
+        self.alloc_from(None)
+    }
+
+    /// Like [`Self::alloc`], using the magazine of `queue` instead of the current cpu's.
+    /// The buffer returns to the same magazine when dropped.
+    pub fn alloc_on(&self, queue: usize) -> Result<DBuff, DError> {
+        self.alloc_from(Some(queue))
+    }
+
+    fn alloc_from(&self, queue: Option<usize>) -> Result<DBuff, DError> {
+        let mut spins = 0;
+        let dvec = loop {
+            match self.inner.alloc(queue) {
+                Slot::Idle(dvec) => break dvec,
+                Slot::Fresh => break self.inner.alloc_fresh()?,
+                Slot::Exhausted => {
+                    let max = self.inner.spins.load(Ordering::Relaxed);
+                    if max != usize::MAX && spins >= max {
+                        return Err(DError::PoolExhausted);
+                    }
+                    spins += 1;
+                    spin_loop();
+                }
             }
         };
 
-        let dvec = DVec::zeros(config.dma_mask, config.size, config.align, config.direction)?;
-        Ok(DBuff {
+        Ok(self.hand_out(dvec, queue))
+    }
+
+    /// Wait for a buffer instead of failing once `max_outstanding` buffers are out.
+    ///
+    /// The future parks in the pool and the oldest waiter is woken when a
+    /// [`DBuff`] is returned, the [`ExhaustPolicy`] is not used. Waiters are
+    /// served in order: new futures queue behind parked ones, and a woken
+    /// waiter that misses the buffer keeps its place.
+    pub fn alloc_async(&self) -> DAlloc {
+        DAlloc {
+            pool: self.clone(),
+            waiter: None,
+        }
+    }
+
+    fn hand_out(&self, dvec: DVec<u8>, queue: Option<usize>) -> DBuff {
+        if self.inner.low_watermark.load(Ordering::Relaxed) > 0 {
+            self.refill();
+        }
+        DBuff {
             data: Some(dvec),
             pool: Arc::downgrade(&self.inner),
-        })
+            queue,
+        }
+    }
+
+    /// Top idle buffers back up to the low watermark, allocating outside the lock.
+    fn refill(&self) {
+        let shared = &self.inner;
+        let count = shared.refill_count(&shared.depot.lock());
+        let config = &shared.config;
+        for _ in 0..count {
+            let Ok(dvec) = DVec::zeros_in(&shared.dev, config.size, config.align, config.direction)
+            else {
+                break;
+            };
+            let mut depot = shared.depot.lock();
+            // another alloc may have refilled meanwhile
+            if shared.refill_count(&depot) == 0 {
+                break;
+            }
+            depot.pool.push_back(dvec);
+        }
+    }
+
+    /// Free idle buffers above the high watermark, returns how many were freed.
+    ///
+    /// Magazines are emptied into the shared pool first.
+    pub fn shrink(&self) -> usize {
+        // never hold a magazine while taking the depot, `alloc` locks them the other way round
+        let mut cached = Vec::new();
+        for magazine in &self.inner.magazines {
+            cached.append(&mut magazine.lock());
+        }
+        let freed = {
+            let mut depot = self.inner.depot.lock();
+            depot.pool.extend(cached);
+            let keep = depot.limits.high_watermark.min(depot.pool.len());
+            depot.pool.split_off(keep)
+        };
+        freed.len()
+    }
+}
+
+/// Future returned by [`DVecPool::alloc_async`].
+pub struct DAlloc {
+    pool: DVecPool,
+    /// id of our entry in the pool's waiter queue
+    waiter: Option<u64>,
+}
+
+impl Future for DAlloc {
+    type Output = Result<DBuff, DError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let shared = &this.pool.inner;
+
+        // only a new poller with nobody queued or a woken waiter may take a
+        // buffer, everyone else waits their turn
+        let turn = match this.waiter {
+            None => shared.waiting.load(Ordering::SeqCst) == 0,
+            Some(id) => shared.is_woken(&shared.depot.lock(), id),
+        };
+        let slot = match turn {
+            true => shared.alloc(None),
+            false => Slot::Exhausted,
+        };
+
+        if let Slot::Exhausted = slot {
+            let waker = {
+                let mut depot = shared.depot.lock();
+                match this.waiter {
+                    Some(id) => shared.rearm_waiter(&mut depot, id, cx.waker()),
+                    None => this.waiter = Some(shared.push_waiter(&mut depot, cx.waker().clone())),
+                }
+                // a buffer may have come back before we were queued, paired
+                // with the `waiting` check in `dealloc`. A waiter woken already
+                // is on its way to take it.
+                let cap = shared.cap.load(Ordering::Relaxed);
+                let woken = depot.waiters.iter().any(|(_, waker)| waker.is_none());
+                match !woken && shared.outstanding.load(Ordering::SeqCst) < cap {
+                    true => shared.take_waker(&mut depot),
+                    false => None,
+                }
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+            return Poll::Pending;
+        }
+
+        if let Some(id) = this.waiter.take() {
+            shared.remove_waiter(&mut shared.depot.lock(), id);
+        }
+        let dvec = match slot {
+            Slot::Idle(dvec) => dvec,
+            _ => match shared.alloc_fresh() {
+                Ok(dvec) => dvec,
+                Err(e) => return Poll::Ready(Err(e)),
+            },
+        };
+        Poll::Ready(Ok(this.pool.hand_out(dvec, None)))
+    }
+}
+
+impl Drop for DAlloc {
+    fn drop(&mut self) {
+        let Some(id) = self.waiter else {
+            return;
+        };
+        let shared = &self.pool.inner;
+        let next = {
+            let mut depot = shared.depot.lock();
+            // woken but gone before taking the buffer, pass the wakeup on
+            match shared.remove_waiter(&mut depot, id) {
+                true => shared.take_waker(&mut depot),
+                false => None,
+            }
+        };
+        if let Some(waker) = next {
+            waker.wake();
+        }
     }
 }
@@ -0,0 +1,121 @@
+use alloc::boxed::Box;
+
+use crate::{DError, DVec, Direction, DmaDevice};
+
+/// A producer/consumer descriptor ring shared with a device.
+///
+/// The driver pushes descriptors at the head and reaps them at the tail once
+/// `is_completed` reports that the device gave them back, e.g. by clearing an
+/// ownership bit. Descriptors are copied in and out of the ring, hence `T: Copy`.
+pub struct DRing<T> {
+    descs: DVec<T>,
+    head: usize,
+    tail: usize,
+    in_flight: usize,
+    is_completed: fn(&T) -> bool,
+    doorbell: Option<Box<dyn FnMut(usize) + Send>>,
+}
+
+impl<T: Copy> DRing<T> {
+    pub fn new(
+        dma_mask: u64,
+        len: usize,
+        align: usize,
+        is_completed: fn(&T) -> bool,
+    ) -> Result<Self, DError> {
+        Self::new_in(&DmaDevice::global(dma_mask), len, align, is_completed)
+    }
+
+    pub fn new_in(
+        dev: &DmaDevice,
+        len: usize,
+        align: usize,
+        is_completed: fn(&T) -> bool,
+    ) -> Result<Self, DError> {
+        Ok(Self {
+            descs: DVec::zeros_in(dev, len, align, Direction::Bidirectional)?,
+            head: 0,
+            tail: 0,
+            in_flight: 0,
+            is_completed,
+            doorbell: None,
+        })
+    }
+
+    /// Called with the new head index after every `push`, to notify the device.
+    pub fn set_doorbell(&mut self, doorbell: impl FnMut(usize) + Send + 'static) {
+        self.doorbell = Some(Box::new(doorbell));
+    }
+
+    /// Bus address of the first descriptor, for the device's ring base register.
+    pub fn bus_addr(&self) -> u64 {
+        self.descs.bus_addr()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.descs.len()
+    }
+
+    /// Descriptors owned by the device.
+    pub fn len(&self) -> usize {
+        self.in_flight
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_flight == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.in_flight == self.capacity()
+    }
+
+    /// Index the next descriptor is pushed to.
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    /// Index of the oldest descriptor owned by the device.
+    pub fn tail(&self) -> usize {
+        self.tail
+    }
+
+    /// Hand `desc` to the device, returns its index or gives it back if the ring is full.
+    pub fn push(&mut self, desc: T) -> Result<usize, T> {
+        if self.is_full() {
+            return Err(desc);
+        }
+        let index = self.head;
+
+        self.descs.set(index, desc);
+
+        self.head = (self.head + 1) % self.capacity();
+        self.in_flight += 1;
+
+        if let Some(doorbell) = self.doorbell.as_mut() {
+            doorbell(self.head);
+        }
+        Ok(index)
+    }
+
+    /// Read the oldest in-flight descriptor without reaping it.
+    pub fn peek(&self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.descs.get(self.tail)
+    }
+
+    /// Reap the oldest descriptor if the device has completed it.
+    pub fn pop_completed(&mut self) -> Option<(usize, T)> {
+        let desc = self.peek()?;
+        if !(self.is_completed)(&desc) {
+            return None;
+        }
+        let index = self.tail;
+
+        self.tail = (self.tail + 1) % self.capacity();
+        self.in_flight -= 1;
+
+        Some((index, desc))
+    }
+}
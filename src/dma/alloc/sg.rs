@@ -0,0 +1,207 @@
+use alloc::vec::Vec;
+use core::{marker::PhantomData, mem::size_of_val, ptr::NonNull};
+
+use crate::{
+    dma::{bounce::Bounce, bounce_synced, map_with_mask},
+    DError, DVec, Direction, DmaDevice, DmaDirection,
+};
+
+/// A scatter-gather list of discontiguous DMA buffers.
+///
+/// Slices pushed into the list are mapped by the list and unmapped when it is
+/// dropped, bounced if they miss the device's dma mask. `DVec`s are already
+/// mapped, so the list only borrows their mapping.
+pub struct DSgList<'a> {
+    dev: DmaDevice,
+    entries: Vec<SgEntry<'a>>,
+    direction: Direction,
+    _marker: PhantomData<&'a mut [u8]>,
+}
+
+struct SgEntry<'a> {
+    dev: DmaDevice,
+    addr: NonNull<u8>,
+    size: usize,
+    bus_addr: u64,
+    direction: Direction,
+    bounce: Option<&'a Bounce>,
+    /// bounce slot of a slice mapped by the list
+    own_bounce: Option<Bounce>,
+    /// the list created this mapping and must unmap it
+    owned: bool,
+}
+
+impl SgEntry<'_> {
+    fn bounce(&self) -> Option<&Bounce> {
+        self.own_bounce.as_ref().or(self.bounce)
+    }
+}
+
+unsafe impl Send for DSgList<'_> {}
+
+impl<'a> DSgList<'a> {
+    /// Create an empty list, `direction` is used for the slices mapped by the list.
+    pub fn new(direction: Direction) -> Self {
+        Self::new_in(&DmaDevice::global(u64::MAX), direction)
+    }
+
+    /// Create an empty list whose slices are mapped for `dev`.
+    pub fn new_in(dev: &DmaDevice, direction: Direction) -> Self {
+        Self {
+            dev: *dev,
+            entries: Vec::new(),
+            direction,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Map a shared slice, only for [`Direction::ToDevice`] lists as the device
+    /// must not write it. Use [`Self::push_slice_mut`] otherwise.
+    pub fn push_slice<T>(&mut self, value: &'a [T]) -> Result<(), DError> {
+        if self.direction != Direction::ToDevice {
+            return Err(DError::ReadOnly {
+                direction: self.direction,
+            });
+        }
+        self.push_raw(value.as_ptr().cast(), size_of_val(value), align_of::<T>())
+    }
+
+    pub fn push_slice_mut<T>(&mut self, value: &'a mut [T]) -> Result<(), DError> {
+        self.push_raw(
+            value.as_mut_ptr().cast(),
+            size_of_val(value),
+            align_of::<T>(),
+        )
+    }
+
+    pub fn push_dvec<T, D: DmaDirection>(&mut self, value: &'a DVec<T, D>) {
+        let common = value.common();
+        let size = common.size;
+        if size == 0 {
+            return;
+        }
+        self.entries.push(SgEntry {
+            dev: common.dev,
+            addr: common.addr.cast(),
+            size,
+            bus_addr: common.bus_addr,
+            direction: common.direction,
+            bounce: common.bounce.as_ref(),
+            own_bounce: None,
+            owned: false,
+        });
+    }
+
+    /// Only called with memory the device may write if it is borrowed mutably,
+    /// so bouncing it back is sound.
+    fn push_raw(&mut self, ptr: *const u8, size: usize, align: usize) -> Result<(), DError> {
+        if size == 0 {
+            return Ok(());
+        }
+        let addr = unsafe { NonNull::new_unchecked(ptr as usize as *mut u8) };
+        let (bus_addr, bounce) = map_with_mask(&self.dev, addr, size, align, self.direction, true)?;
+
+        self.entries.push(SgEntry {
+            dev: self.dev,
+            addr,
+            size,
+            bus_addr,
+            direction: self.direction,
+            bounce: None,
+            own_bounce: bounce,
+            owned: true,
+        });
+        Ok(())
+    }
+
+    /// Number of buffers pushed into the list.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total size in bytes of all buffers.
+    pub fn total_size(&self) -> usize {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    /// Iterate `(bus_addr, len)` segments, merging buffers whose bus addresses are contiguous.
+    pub fn segments(&self) -> DSgSegments<'_> {
+        DSgSegments {
+            entries: &self.entries,
+            pos: 0,
+        }
+    }
+
+    pub fn prepare_read_all(&self) {
+        for e in &self.entries {
+            match e.bounce() {
+                Some(bounce) => {
+                    bounce_synced(e.addr, e.size, false);
+                    bounce.prepare_read(e.direction, e.addr, e.size)
+                }
+                None => e.dev.sync_for_cpu(e.direction, e.addr, e.size),
+            }
+        }
+    }
+
+    pub fn confirm_write_all(&self) {
+        for e in &self.entries {
+            match e.bounce() {
+                Some(bounce) => {
+                    bounce_synced(e.addr, e.size, true);
+                    bounce.confirm_write(e.direction, e.addr, e.size)
+                }
+                None => e.dev.sync_for_device(e.direction, e.addr, e.size),
+            }
+        }
+    }
+}
+
+impl Drop for DSgList<'_> {
+    fn drop(&mut self) {
+        for e in self.entries.iter().filter(|e| e.owned) {
+            match &e.own_bounce {
+                // like `DSliceMut`, hand what the device wrote back to the original buffer
+                Some(bounce) => {
+                    bounce_synced(e.addr, e.size, false);
+                    bounce.prepare_read(e.direction, e.addr, e.size)
+                }
+                None => e.dev.unmap(e.addr, e.size),
+            }
+        }
+    }
+}
+
+pub struct DSgSegments<'a> {
+    entries: &'a [SgEntry<'a>],
+    pos: usize,
+}
+
+impl Iterator for DSgSegments<'_> {
+    type Item = (u64, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.entries.get(self.pos)?;
+        let bus_addr = first.bus_addr;
+        let mut len = first.size;
+        self.pos += 1;
+
+        while let Some(e) = self.entries.get(self.pos) {
+            if bus_addr + len as u64 != e.bus_addr {
+                break;
+            }
+            len += e.size;
+            self.pos += 1;
+        }
+
+        Some((bus_addr, len))
+    }
+}
@@ -0,0 +1,114 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{DBuff, DError, DVecConfig, DVecPool, Direction, DmaDevice};
+
+/// One class of a [`DSizeClassPool`].
+#[derive(Debug, Clone, Copy)]
+pub struct DSizeClass {
+    pub size: usize,
+    pub align: usize,
+    /// buffers allocated up front
+    pub cap: usize,
+}
+
+/// Counters of one class, see [`DSizeClassPool::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DSizeClassStats {
+    pub size: usize,
+    /// successful `alloc` calls served by this class
+    pub allocs: u64,
+    /// `alloc` calls this class could not serve
+    pub failures: u64,
+    pub outstanding: usize,
+    pub idle: usize,
+}
+
+struct Class {
+    size: usize,
+    pool: DVecPool,
+    allocs: AtomicU64,
+    failures: AtomicU64,
+}
+
+/// Several [`DVecPool`]s of different buffer sizes behind one `alloc(len)`.
+///
+/// Each buffer returns to the class it came from when dropped.
+#[derive(Clone)]
+pub struct DSizeClassPool {
+    classes: Arc<[Class]>,
+}
+
+impl DSizeClassPool {
+    pub fn new(dma_mask: u64, direction: Direction, classes: &[DSizeClass]) -> Self {
+        Self::new_in(&DmaDevice::global(dma_mask), direction, classes)
+    }
+
+    pub fn new_in(dev: &DmaDevice, direction: Direction, classes: &[DSizeClass]) -> Self {
+        let mut classes: Vec<DSizeClass> = classes.to_vec();
+        classes.sort_by_key(|c| c.size);
+
+        let classes = classes
+            .iter()
+            .map(|c| Class {
+                size: c.size,
+                pool: DVecPool::new_pool_in(
+                    dev,
+                    DVecConfig {
+                        dma_mask: dev.dma_mask(),
+                        align: c.align,
+                        size: c.size,
+                        direction,
+                    },
+                    c.cap,
+                ),
+                allocs: AtomicU64::new(0),
+                failures: AtomicU64::new(0),
+            })
+            .collect();
+        Self { classes }
+    }
+
+    /// A buffer from the smallest class that fits `len`, truncated to `len`.
+    pub fn alloc(&self, len: usize) -> Result<DBuff, DError> {
+        let class = self
+            .classes
+            .iter()
+            .find(|c| c.size >= len)
+            .ok_or(DError::NoSizeClass { len })?;
+
+        match class.pool.alloc() {
+            Ok(mut buff) => {
+                class.allocs.fetch_add(1, Ordering::Relaxed);
+                buff.truncate(len);
+                Ok(buff)
+            }
+            Err(e) => {
+                class.failures.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// The pool behind the class of buffer size `size`, e.g. to set its limits.
+    pub fn class(&self, size: usize) -> Option<&DVecPool> {
+        self.classes
+            .iter()
+            .find(|c| c.size == size)
+            .map(|c| &c.pool)
+    }
+
+    /// Per class counters, smallest class first.
+    pub fn stats(&self) -> Vec<DSizeClassStats> {
+        self.classes
+            .iter()
+            .map(|c| DSizeClassStats {
+                size: c.size,
+                allocs: c.allocs.load(Ordering::Relaxed),
+                failures: c.failures.load(Ordering::Relaxed),
+                outstanding: c.pool.outstanding(),
+                idle: c.pool.idle(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,206 @@
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{DBox, DBuff, DVec, DmaDirection};
+
+/// A buffer that can be handed to a device as a whole.
+pub trait DmaBuffer {
+    fn bus_addr(&self) -> u64;
+
+    fn confirm_write_all(&self);
+
+    fn prepare_read_all(&self);
+}
+
+// through the untyped common part, syncing for the cpu is a no-op for `ToDevice`
+impl<T, D: DmaDirection> DmaBuffer for DVec<T, D> {
+    fn bus_addr(&self) -> u64 {
+        DVec::bus_addr(self)
+    }
+
+    fn confirm_write_all(&self) {
+        DVec::confirm_write_all(self)
+    }
+
+    fn prepare_read_all(&self) {
+        self.common().prepare_read_all()
+    }
+}
+
+impl<T, D: DmaDirection> DmaBuffer for DBox<T, D> {
+    fn bus_addr(&self) -> u64 {
+        DBox::bus_addr(self)
+    }
+
+    fn confirm_write_all(&self) {
+        DBox::confirm_write_all(self)
+    }
+
+    fn prepare_read_all(&self) {
+        self.common().prepare_read_all()
+    }
+}
+
+impl DmaBuffer for DBuff {
+    fn bus_addr(&self) -> u64 {
+        DVec::bus_addr(self)
+    }
+
+    fn confirm_write_all(&self) {
+        DVec::confirm_write_all(self)
+    }
+
+    fn prepare_read_all(&self) {
+        DVec::prepare_read_all(self)
+    }
+}
+
+#[derive(Default)]
+struct Completion {
+    done: AtomicBool,
+    waker: AtomicWaker,
+}
+
+const WAITING: u8 = 0;
+const REGISTERING: u8 = 0b01;
+const WAKING: u8 = 0b10;
+
+/// A waker slot that `wake` never blocks on, so it can run in an IRQ handler
+/// interrupting `register` on the same core. Like `futures`' `AtomicWaker`,
+/// whoever finds the other side busy leaves the wakeup to it.
+#[derive(Default)]
+struct AtomicWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// `waker` is only accessed by whoever moved `state` away from `WAITING`.
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    /// Only one task registers at a time, `DTransfer::poll` takes `&mut self`.
+    fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(
+            WAITING,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                unsafe { *self.waker.get() = Some(waker.clone()) };
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // `wake` ran meanwhile and left the waker to us
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // a wake is in progress, poll again
+            Err(_) => waker.wake_by_ref(),
+        }
+    }
+
+    fn wake(&self) {
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) == WAITING {
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Signals the end of a [`DTransfer`], usually from the device's IRQ handler.
+#[derive(Clone)]
+pub struct DCompleter {
+    inner: Arc<Completion>,
+}
+
+impl DCompleter {
+    /// Never blocks, safe to call from an IRQ handler.
+    pub fn complete(&self) {
+        self.inner.done.store(true, Ordering::Release);
+        self.inner.waker.wake();
+    }
+}
+
+/// A buffer currently owned by the device.
+///
+/// The CPU can't touch the buffer until the transfer resolves, which happens
+/// after [`DCompleter::complete`] and gives the buffer back with its cache
+/// invalidated. Dropping an unfinished transfer leaks the buffer, because the
+/// device may still be writing to it.
+pub struct DTransfer<B: DmaBuffer> {
+    buff: Option<B>,
+    completion: Arc<Completion>,
+}
+
+impl<B: DmaBuffer> DTransfer<B> {
+    /// Write the buffer back to memory and hand it to the device.
+    pub fn start(buff: B) -> (Self, DCompleter) {
+        buff.confirm_write_all();
+
+        let completion = Arc::new(Completion::default());
+        let completer = DCompleter {
+            inner: completion.clone(),
+        };
+        (
+            Self {
+                buff: Some(buff),
+                completion,
+            },
+            completer,
+        )
+    }
+
+    /// Bus address to program into the device.
+    pub fn bus_addr(&self) -> u64 {
+        self.buff.as_ref().unwrap().bus_addr()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completion.done.load(Ordering::Acquire)
+    }
+}
+
+impl<B: DmaBuffer + Unpin> Future for DTransfer<B> {
+    type Output = B;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.is_complete() {
+            self.completion.waker.register(cx.waker());
+            // the device may have completed before the waker was registered
+            if !self.is_complete() {
+                return Poll::Pending;
+            }
+        }
+
+        let buff = self.buff.take().expect("DTransfer polled after completion");
+        buff.prepare_read_all();
+        Poll::Ready(buff)
+    }
+}
+
+impl<B: DmaBuffer> Drop for DTransfer<B> {
+    fn drop(&mut self) {
+        if !self.is_complete() {
+            if let Some(buff) = self.buff.take() {
+                core::mem::forget(buff);
+            }
+        }
+    }
+}
@@ -1,61 +1,132 @@
 #[cfg(feature = "alloc")]
 use alloc::vec::Vec;
-use core::{alloc::Layout, mem::size_of, ops::Index};
+use core::{
+    alloc::Layout,
+    marker::PhantomData,
+    mem::size_of,
+    ops::{Index, RangeBounds},
+};
 
 use super::DCommon;
-use crate::{dma::alloc::DError, Direction};
-
-pub struct DVec<T> {
+use crate::{
+    dma::{
+        cpu_wrote,
+        dir::{DeviceReads, DeviceWrites, DmaDirection},
+        guard::{DReadGuard, DWriteGuard},
+    },
+    DError, Direction, DmaDevice,
+};
+
+/// A dma buffer of `T`, `D` is a marker such as [`crate::ToDevice`] or the runtime [`Direction`].
+pub struct DVec<T, D = Direction> {
     inner: DCommon<T>,
+    _dir: PhantomData<D>,
 }
 
-impl<T> DVec<T> {
+impl<T, D: DmaDirection> DVec<T, D> {
     const T_SIZE: usize = size_of::<T>();
 
-    pub fn zeros(
-        dma_mask: u64,
+    pub fn zeros(dma_mask: u64, len: usize, align: usize, direction: D) -> Result<Self, DError> {
+        Self::zeros_in(&DmaDevice::global(dma_mask), len, align, direction)
+    }
+
+    pub fn zeros_in(
+        dev: &DmaDevice,
         len: usize,
         align: usize,
-        direction: Direction,
+        direction: D,
     ) -> Result<Self, DError> {
         let size = len * size_of::<T>();
         let layout = Layout::from_size_align(size, align)?;
 
         Ok(Self {
-            inner: DCommon::zeros(dma_mask, layout, direction)?,
+            inner: DCommon::zeros(dev, layout, direction.direction())?,
+            _dir: PhantomData,
         })
     }
 
-    pub fn from_vec(dma_mask: u64, value: Vec<T>, direction: Direction) -> Result<Self, DError> {
+    pub fn from_vec(dma_mask: u64, value: Vec<T>, direction: D) -> Result<Self, DError> {
+        Self::from_vec_in(&DmaDevice::global(dma_mask), value, direction)
+    }
+
+    pub fn from_vec_in(dev: &DmaDevice, value: Vec<T>, direction: D) -> Result<Self, DError> {
         Ok(Self {
-            inner: DCommon::from_vec(dma_mask, value, direction)?,
+            inner: DCommon::from_vec(dev, value, direction.direction())?,
+            _dir: PhantomData,
         })
     }
 
+    /// Unmap and take the elements back. A buffer from [`Self::from_vec`] is
+    /// handed back as is, others are copied out of the [`crate::Osal::alloc`] memory.
     pub fn to_vec(mut self) -> Vec<T> {
+        self.inner.prepare_read_all();
+        let len = self.len();
         unsafe {
-            self.inner
-                .prepare_read(self.inner.addr.cast(), self.inner.layout.size());
-            crate::unmap(self.inner.addr.cast(), self.inner.layout.size());
-            let len = self.len();
+            if !self.inner.from_vec {
+                let mut v = Vec::with_capacity(len);
+                core::ptr::copy_nonoverlapping(self.inner.addr.as_ptr(), v.as_mut_ptr(), len);
+                v.set_len(len);
+                return v;
+            }
+            self.inner.unmap();
+            let cap = self.capacity();
 
             self.inner.layout = Layout::from_size_align_unchecked(0, 0x1000);
-            Vec::from_raw_parts(self.inner.addr.as_ptr(), len, len)
+            Vec::from_raw_parts(self.inner.addr.as_ptr(), len, cap)
         }
     }
 
     pub fn len(&self) -> usize {
-        self.inner.layout.size() / size_of::<T>()
+        self.inner.size / size_of::<T>()
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
+    /// Elements that fit without reallocating, allocations are padded to whole cache lines.
+    pub fn capacity(&self) -> usize {
+        self.inner.layout.size() / size_of::<T>()
+    }
+
     pub fn bus_addr(&self) -> u64 {
         self.inner.bus_addr
     }
 
+    /// [`Self::sync_for_device`] on the whole buffer.
+    pub fn confirm_write_all(&self) {
+        self.inner.confirm_write_all();
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.inner.addr.as_ptr()
+    }
+
+    pub(super) fn common(&self) -> &DCommon<T> {
+        &self.inner
+    }
+
+    pub(super) fn set_len(&mut self, len: usize) {
+        assert!(len <= self.capacity());
+        self.inner.size = len * Self::T_SIZE;
+    }
+
+    /// [`DmaDevice::sync_for_device`] on the elements in `range`.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds.
+    pub fn sync_for_device(&self, range: impl RangeBounds<usize>) {
+        self.inner.confirm_write_range(range);
+    }
+
+    #[deprecated(note = "use `sync_for_device`")]
+    pub fn confirm_write_range(&self, range: impl RangeBounds<usize>) {
+        self.sync_for_device(range);
+    }
+}
+
+/// Reads that invalidate first, the device may have written the buffer.
+impl<T, D: DeviceWrites> DVec<T, D> {
     pub fn get(&self, index: usize) -> Option<T> {
         if index >= self.len() {
             return None;
@@ -70,6 +141,33 @@ impl<T> DVec<T> {
         }
     }
 
+    /// Borrow the buffer through a [`DReadGuard`].
+    pub fn read_guard(&self) -> DReadGuard<'_, T> {
+        let slice = unsafe { core::slice::from_raw_parts(self.inner.addr.as_ptr(), self.len()) };
+        DReadGuard::new(&self.inner, slice)
+    }
+
+    /// [`Self::sync_for_cpu`] on the whole buffer.
+    pub fn prepare_read_all(&self) {
+        self.inner.prepare_read_all();
+    }
+
+    /// [`DmaDevice::sync_for_cpu`] on the elements in `range`.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds.
+    pub fn sync_for_cpu(&self, range: impl RangeBounds<usize>) {
+        self.inner.prepare_read_range(range);
+    }
+
+    #[deprecated(note = "use `sync_for_cpu`")]
+    pub fn prepare_read_range(&self, range: impl RangeBounds<usize>) {
+        self.sync_for_cpu(range);
+    }
+}
+
+/// Cpu writes, flushed for the device to read.
+impl<T, D: DeviceReads> DVec<T, D> {
     pub fn set(&mut self, index: usize, value: T) {
         assert!(
             index < self.len(),
@@ -83,7 +181,8 @@ impl<T> DVec<T> {
 
             ptr.write_volatile(value);
 
-            self.inner.confirm_write(ptr.cast(), Self::T_SIZE);
+            cpu_wrote(ptr.cast(), Self::T_SIZE);
+            self.inner.write_back(ptr.cast(), Self::T_SIZE);
         }
     }
 
@@ -91,21 +190,15 @@ impl<T> DVec<T> {
         unsafe { core::slice::from_raw_parts_mut(self.inner.addr.as_ptr(), self.len()) }
     }
 
-    pub fn confirm_write_all(&self) {
-        self.inner.confirm_write_all();
-    }
-
-    pub fn as_ptr(&self) -> *mut T {
-        self.inner.addr.as_ptr()
-    }
-
-    pub fn prepare_read_all(&self) {
-        self.inner
-            .prepare_read(self.inner.addr.cast(), self.inner.layout.size());
+    /// Borrow the buffer mutably through a [`DWriteGuard`].
+    pub fn write_guard(&mut self) -> DWriteGuard<'_, T> {
+        let slice =
+            unsafe { core::slice::from_raw_parts_mut(self.inner.addr.as_ptr(), self.len()) };
+        DWriteGuard::new(&self.inner, slice)
     }
 }
 
-impl<T> Index<usize> for DVec<T> {
+impl<T, D: DeviceWrites> Index<usize> for DVec<T, D> {
     type Output = T;
 
     fn index(&self, index: usize) -> &Self::Output {
@@ -119,20 +212,75 @@ impl<T> Index<usize> for DVec<T> {
     }
 }
 
-impl<T: Copy> DVec<T> {
+impl<T: Copy, D: DeviceReads> DVec<T, D> {
     pub fn copy_from_slice(&mut self, src: &[T]) {
         assert!(src.len() <= self.len());
 
         self.as_slice_mut().copy_from_slice(src);
 
-        self.inner.confirm_write_all();
+        cpu_wrote(self.inner.addr.cast(), size_of_val(src));
+        self.inner
+            .write_back(self.inner.addr.cast(), size_of_val(src));
+    }
+
+    /// Append `value`, returns the new bus address if the buffer had to move.
+    pub fn push(&mut self, value: T) -> Result<Option<u64>, DError> {
+        let moved = self.reserve(1)?;
+        let index = self.len();
+        self.inner.size += Self::T_SIZE;
+        self.set(index, value);
+        Ok(moved)
+    }
+
+    /// Append `src`, returns the new bus address if the buffer had to move.
+    pub fn extend_from_slice(&mut self, src: &[T]) -> Result<Option<u64>, DError> {
+        let moved = self.reserve(src.len())?;
+        let start = self.len();
+        self.inner.size += src.len() * Self::T_SIZE;
+        self.as_slice_mut()[start..].copy_from_slice(src);
+        cpu_wrote(
+            unsafe { self.inner.addr.add(start) }.cast(),
+            size_of_val(src),
+        );
+        self.inner.write_back_range(start..);
+        Ok(moved)
+    }
+}
+
+impl<T: Copy, D: DmaDirection> DVec<T, D> {
+    /// Make room for `additional` more elements.
+    ///
+    /// Growing reallocates through [`crate::Osal::alloc`] and remaps, so the
+    /// device must be told about the new bus address, which is returned if it changed.
+    pub fn reserve(&mut self, additional: usize) -> Result<Option<u64>, DError> {
+        let required = self.len().checked_add(additional).ok_or(DError::NoMemory)?;
+        if required <= self.capacity() {
+            return Ok(None);
+        }
+        let capacity = required.max(self.capacity() * 2);
+        let size = capacity
+            .checked_mul(Self::T_SIZE)
+            .ok_or(DError::LayoutError)?;
+
+        self.inner.grow(size)?;
+        Ok(Some(self.bus_addr()))
+    }
+
+    /// Shorten to `len` elements, the capacity and mapping are kept.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            self.inner.size = len * Self::T_SIZE;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
     }
 }
 
-impl<T> AsRef<[T]> for DVec<T> {
+impl<T, D: DeviceWrites> AsRef<[T]> for DVec<T, D> {
     fn as_ref(&self) -> &[T] {
-        self.inner
-            .prepare_read(self.inner.addr.cast(), self.inner.layout.size());
+        self.inner.prepare_read_all();
         unsafe { core::slice::from_raw_parts(self.inner.addr.as_ptr(), self.len()) }
     }
 }
@@ -0,0 +1,261 @@
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{Direction, DmaDevice};
+
+/// Size of one bounce slot, the same as the `IO_TLB_SIZE` of Linux swiotlb.
+pub const BOUNCE_SLOT_SIZE: usize = 0x800;
+
+const BITMAP_WORDS: usize = 128;
+const MAX_SLOTS: usize = BITMAP_WORDS * 64;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BounceStats {
+    pub total_slots: usize,
+    pub used_slots: usize,
+    pub peak_used_slots: usize,
+    /// mappings that fell back to the bounce region
+    pub mapped: usize,
+    /// fallbacks that failed because no slots under the dma mask were free
+    pub failed: usize,
+    /// bytes copied into the bounce region on `confirm_write`
+    pub bytes_to_device: usize,
+    /// bytes copied out of the bounce region on `prepare_read`
+    pub bytes_from_device: usize,
+}
+
+struct BouncePool {
+    addr: NonNull<u8>,
+    bus_addr: u64,
+    slots: usize,
+    bitmap: [u64; BITMAP_WORDS],
+    used: usize,
+    peak_used: usize,
+    mapped: usize,
+    failed: usize,
+}
+
+unsafe impl Send for BouncePool {}
+
+/// A low-memory region used when a mapping does not meet the dma mask.
+///
+/// Only devices whose [`crate::Osal::bounce_region`] returns the region bounce
+/// into it, the bus addresses are only valid behind that `Osal`.
+///
+/// ```
+/// # use dma_api::BounceRegion;
+/// static BOUNCE: BounceRegion = BounceRegion::new();
+/// ```
+pub struct BounceRegion {
+    pool: Mutex<Option<BouncePool>>,
+    bytes_to_device: AtomicUsize,
+    bytes_from_device: AtomicUsize,
+}
+
+impl BounceRegion {
+    pub const fn new() -> Self {
+        Self {
+            pool: Mutex::new(None),
+            bytes_to_device: AtomicUsize::new(0),
+            bytes_from_device: AtomicUsize::new(0),
+        }
+    }
+
+    /// Hand the memory to the region.
+    ///
+    /// Only the first `BOUNCE_SLOT_SIZE * 8192` bytes of the memory are used,
+    /// and like [`crate::init`] only the first call takes effect.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to `size` bytes of memory that is reserved for dma-api
+    /// for the rest of the program, and `bus_addr` must be its bus address.
+    pub unsafe fn init(&self, addr: NonNull<u8>, bus_addr: u64, size: usize) {
+        let mut pool = self.pool.lock();
+        if pool.is_some() {
+            return;
+        }
+        let slots = (size / BOUNCE_SLOT_SIZE).min(MAX_SLOTS);
+        pool.replace(BouncePool {
+            addr,
+            bus_addr,
+            slots,
+            bitmap: [0; BITMAP_WORDS],
+            used: 0,
+            peak_used: 0,
+            mapped: 0,
+            failed: 0,
+        });
+    }
+
+    /// Usage of the region, `None` if [`Self::init`] was never called.
+    pub fn stats(&self) -> Option<BounceStats> {
+        let pool = self.pool.lock();
+        let pool = pool.as_ref()?;
+        Some(BounceStats {
+            total_slots: pool.slots,
+            used_slots: pool.used,
+            peak_used_slots: pool.peak_used,
+            mapped: pool.mapped,
+            failed: pool.failed,
+            bytes_to_device: self.bytes_to_device.load(Ordering::Relaxed),
+            bytes_from_device: self.bytes_from_device.load(Ordering::Relaxed),
+        })
+    }
+}
+
+impl Default for BounceRegion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BouncePool {
+    fn is_free(&self, slot: usize) -> bool {
+        self.bitmap[slot / 64] & (1 << (slot % 64)) == 0
+    }
+
+    fn set(&mut self, start: usize, count: usize, used: bool) {
+        for slot in start..start + count {
+            if used {
+                self.bitmap[slot / 64] |= 1 << (slot % 64);
+            } else {
+                self.bitmap[slot / 64] &= !(1 << (slot % 64));
+            }
+        }
+    }
+
+    fn find(&self, count: usize, align: usize, dma_mask: u64) -> Option<usize> {
+        let mut start = 0;
+        while start + count <= self.slots {
+            let bus_addr = self.bus_addr + (start * BOUNCE_SLOT_SIZE) as u64;
+            let end = bus_addr + (count * BOUNCE_SLOT_SIZE) as u64 - 1;
+            if end & dma_mask != end {
+                return None;
+            }
+            if !bus_addr.is_multiple_of(align as u64) {
+                start += 1;
+                continue;
+            }
+            match (start..start + count).find(|&s| !self.is_free(s)) {
+                Some(used) => start = used + 1,
+                None => return Some(start),
+            }
+        }
+        None
+    }
+}
+
+/// A mapping that was redirected into the bounce region.
+pub(crate) struct Bounce {
+    dev: DmaDevice,
+    region: &'static BounceRegion,
+    /// the original buffer, the cpu side of the mapping
+    orig: NonNull<u8>,
+    size: usize,
+    addr: NonNull<u8>,
+    bus_addr: u64,
+    start: usize,
+    count: usize,
+}
+
+unsafe impl Send for Bounce {}
+unsafe impl Sync for Bounce {}
+
+impl Bounce {
+    /// Take slots for the `size` bytes at `orig`, at a bus address that is
+    /// `align` aligned and under the dma mask of `dev`.
+    ///
+    /// `None` if the `Osal` of `dev` has no bounce region.
+    pub fn alloc(dev: &DmaDevice, orig: NonNull<u8>, size: usize, align: usize) -> Option<Self> {
+        let region = dev.osal().bounce_region()?;
+        let mut guard = region.pool.lock();
+        let pool = guard.as_mut()?;
+        let count = size.max(1).div_ceil(BOUNCE_SLOT_SIZE);
+
+        let Some(start) = pool.find(count, align.max(1), dev.dma_mask()) else {
+            pool.failed += 1;
+            return None;
+        };
+
+        pool.set(start, count, true);
+        pool.used += count;
+        pool.peak_used = pool.peak_used.max(pool.used);
+        pool.mapped += 1;
+
+        let offset = start * BOUNCE_SLOT_SIZE;
+        Some(Self {
+            dev: *dev,
+            region,
+            orig,
+            size,
+            addr: unsafe { pool.addr.add(offset) },
+            bus_addr: pool.bus_addr + offset as u64,
+            start,
+            count,
+        })
+    }
+
+    pub fn bus_addr(&self) -> u64 {
+        self.bus_addr
+    }
+
+    /// Copy the whole original buffer in, so the device never sees stale bounce data.
+    pub fn fill(&self, direction: Direction) {
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.orig.as_ptr(), self.addr.as_ptr(), self.size)
+        };
+        self.dev.clean_for_device(direction, self.addr, self.size);
+    }
+
+    /// `ptr` lies inside the original buffer.
+    pub fn prepare_read(&self, direction: Direction, ptr: NonNull<u8>, size: usize) {
+        if !matches!(direction, Direction::FromDevice | Direction::Bidirectional) {
+            return;
+        }
+        unsafe {
+            let src = self
+                .addr
+                .add(ptr.as_ptr() as usize - self.orig.as_ptr() as usize);
+            self.dev.dma_rmb();
+            self.dev.invalidate_lines(src, size);
+            core::ptr::copy_nonoverlapping(src.as_ptr(), ptr.as_ptr(), size);
+        }
+        self.region
+            .bytes_from_device
+            .fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// `ptr` lies inside the original buffer.
+    pub fn confirm_write(&self, direction: Direction, ptr: NonNull<u8>, size: usize) {
+        if !matches!(direction, Direction::ToDevice | Direction::Bidirectional) {
+            return;
+        }
+        unsafe {
+            let dst = self
+                .addr
+                .add(ptr.as_ptr() as usize - self.orig.as_ptr() as usize);
+            core::ptr::copy_nonoverlapping(ptr.as_ptr(), dst.as_ptr(), size);
+            self.dev.flush_lines(dst, size);
+            self.dev.dma_wmb();
+        }
+        self.region
+            .bytes_to_device
+            .fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Bounce {
+    fn drop(&mut self) {
+        #[cfg(feature = "dma-debug")]
+        crate::debug::on_unmap(self.orig, self.size);
+        if let Some(pool) = self.region.pool.lock().as_mut() {
+            pool.set(self.start, self.count, false);
+            pool.used -= self.count;
+        }
+    }
+}
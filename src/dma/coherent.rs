@@ -0,0 +1,171 @@
+use core::{alloc::Layout, mem::size_of, ops::Index, ptr::NonNull};
+
+use crate::{dma::check_dma_mask, CoherentMem, DError, DmaDevice};
+
+/// Uncached memory from [`crate::Osal::alloc_coherent`], accesses need no cache maintenance.
+struct CCommon<T> {
+    dev: DmaDevice,
+    addr: NonNull<T>,
+    bus_addr: u64,
+    layout: Layout,
+}
+
+unsafe impl<T: Send> Send for CCommon<T> {}
+
+impl<T> CCommon<T> {
+    fn zeros(dev: &DmaDevice, layout: Layout) -> Result<Self, DError> {
+        if layout.size() == 0 {
+            // nothing to allocate, `Drop` skips empty buffers as well
+            return Ok(Self {
+                dev: *dev,
+                addr: NonNull::dangling(),
+                bus_addr: 0,
+                layout,
+            });
+        }
+        let mem = dev
+            .alloc_coherent(layout)
+            .ok_or(DError::CoherentUnsupported)?;
+        if let Err(e) = check_dma_mask(dev.dma_mask(), mem.bus_addr) {
+            dev.dealloc_coherent(mem, layout);
+            return Err(e);
+        }
+        unsafe { mem.cpu_addr.write_bytes(0, layout.size()) };
+
+        Ok(Self {
+            dev: *dev,
+            addr: mem.cpu_addr.cast(),
+            bus_addr: mem.bus_addr,
+            layout,
+        })
+    }
+}
+
+impl<T> Drop for CCommon<T> {
+    fn drop(&mut self) {
+        if self.layout.size() == 0 {
+            return;
+        }
+        self.dev.dealloc_coherent(
+            CoherentMem {
+                cpu_addr: self.addr.cast(),
+                bus_addr: self.bus_addr,
+            },
+            self.layout,
+        );
+    }
+}
+
+pub struct DCoherentVec<T> {
+    inner: CCommon<T>,
+}
+
+impl<T> DCoherentVec<T> {
+    pub fn zeros(dma_mask: u64, len: usize, align: usize) -> Result<Self, DError> {
+        Self::zeros_in(&DmaDevice::global(dma_mask), len, align)
+    }
+
+    pub fn zeros_in(dev: &DmaDevice, len: usize, align: usize) -> Result<Self, DError> {
+        let layout = Layout::from_size_align(len * size_of::<T>(), align)?;
+        Ok(Self {
+            inner: CCommon::zeros(dev, layout)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.layout.size() / size_of::<T>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn bus_addr(&self) -> u64 {
+        self.inner.bus_addr
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.inner.addr.as_ptr()
+    }
+
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index >= self.len() {
+            return None;
+        }
+        unsafe { Some(self.inner.addr.add(index).read_volatile()) }
+    }
+
+    pub fn set(&mut self, index: usize, value: T) {
+        assert!(
+            index < self.len(),
+            "index out of range, index: {},len: {}",
+            index,
+            self.len()
+        );
+        unsafe { self.inner.addr.add(index).write_volatile(value) }
+    }
+}
+
+impl<T> Index<usize> for DCoherentVec<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        assert!(index < self.len());
+        unsafe { self.inner.addr.add(index).as_ref() }
+    }
+}
+
+impl<T> AsRef<[T]> for DCoherentVec<T> {
+    fn as_ref(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.inner.addr.as_ptr(), self.len()) }
+    }
+}
+
+impl<T> AsMut<[T]> for DCoherentVec<T> {
+    fn as_mut(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.inner.addr.as_ptr(), self.len()) }
+    }
+}
+
+pub struct DCoherentBox<T> {
+    inner: CCommon<T>,
+}
+
+impl<T> DCoherentBox<T> {
+    pub fn zero_with_align(dma_mask: u64, align: usize) -> Result<Self, DError> {
+        Self::zero_with_align_in(&DmaDevice::global(dma_mask), align)
+    }
+
+    pub fn zero_with_align_in(dev: &DmaDevice, align: usize) -> Result<Self, DError> {
+        let layout = Layout::from_size_align(size_of::<T>(), align)?;
+        Ok(Self {
+            inner: CCommon::zeros(dev, layout)?,
+        })
+    }
+
+    pub fn zero(dma_mask: u64) -> Result<Self, DError> {
+        Self::zero_in(&DmaDevice::global(dma_mask))
+    }
+
+    pub fn zero_in(dev: &DmaDevice) -> Result<Self, DError> {
+        Ok(Self {
+            inner: CCommon::zeros(dev, Layout::new::<T>())?,
+        })
+    }
+
+    pub fn bus_addr(&self) -> u64 {
+        self.inner.bus_addr
+    }
+
+    pub fn read(&self) -> T {
+        unsafe { self.inner.addr.read_volatile() }
+    }
+
+    pub fn write(&mut self, value: T) {
+        unsafe { self.inner.addr.write_volatile(value) }
+    }
+
+    pub fn modify(&mut self, f: impl FnOnce(&mut T)) {
+        unsafe { f(self.inner.addr.as_mut()) }
+    }
+}
@@ -0,0 +1,100 @@
+//! Directions as types, so misuse of a buffer fails to compile.
+//!
+//! [`crate::DVec`], [`crate::DBox`], [`DSlice`](crate::DSlice) and
+//! [`DSliceMut`](crate::DSliceMut) take the direction as a parameter. Cpu
+//! writes need [`DeviceReads`] and reads that invalidate need
+//! [`DeviceWrites`]. The runtime [`Direction`] is the default and allows both,
+//! so code passing a `Direction` keeps its runtime behaviour.
+
+use crate::Direction;
+
+mod sealed {
+    /// Only the markers here and [`crate::Direction`] are directions, an outside
+    /// marker could claim [`super::DeviceWrites`] and map `ToDevice`.
+    pub trait Sealed {}
+
+    impl Sealed for super::ToDevice {}
+    impl Sealed for super::FromDevice {}
+    impl Sealed for super::Bidirectional {}
+    impl Sealed for crate::Direction {}
+}
+
+/// A direction known when the buffer is created.
+///
+/// ```compile_fail
+/// use dma_api::*;
+///
+/// let tx: DVec<u8, ToDevice> = DVec::zeros(u64::MAX, 64, 64, ToDevice).unwrap();
+/// // the device never writes `tx`
+/// tx.get(0);
+/// ```
+pub trait DmaDirection: Copy + sealed::Sealed {
+    fn direction(self) -> Direction;
+}
+
+/// The device reads the buffer, so the cpu may write it.
+pub trait DeviceReads: DmaDirection {}
+
+/// The device writes the buffer, so the cpu reads it after invalidating.
+///
+/// Sealed, a marker of another crate can't claim it:
+///
+/// ```compile_fail
+/// use dma_api::*;
+///
+/// #[derive(Clone, Copy)]
+/// struct Fake;
+///
+/// impl DmaDirection for Fake {
+///     fn direction(self) -> Direction {
+///         Direction::ToDevice
+///     }
+/// }
+/// impl DeviceWrites for Fake {}
+/// ```
+pub trait DeviceWrites: DmaDirection {}
+
+/// Marker for [`Direction::ToDevice`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ToDevice;
+
+/// Marker for [`Direction::FromDevice`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FromDevice;
+
+/// Marker for [`Direction::Bidirectional`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Bidirectional;
+
+impl DmaDirection for ToDevice {
+    fn direction(self) -> Direction {
+        Direction::ToDevice
+    }
+}
+
+impl DmaDirection for FromDevice {
+    fn direction(self) -> Direction {
+        Direction::FromDevice
+    }
+}
+
+impl DmaDirection for Bidirectional {
+    fn direction(self) -> Direction {
+        Direction::Bidirectional
+    }
+}
+
+/// Checked at runtime only, like before the markers existed.
+impl DmaDirection for Direction {
+    fn direction(self) -> Direction {
+        self
+    }
+}
+
+impl DeviceReads for ToDevice {}
+impl DeviceReads for Bidirectional {}
+impl DeviceReads for Direction {}
+
+impl DeviceWrites for FromDevice {}
+impl DeviceWrites for Bidirectional {}
+impl DeviceWrites for Direction {}
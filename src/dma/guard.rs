@@ -0,0 +1,93 @@
+use core::ops::{Deref, DerefMut, Range, RangeBounds};
+
+use super::{cpu_wrote, elem_range};
+
+/// Element range syncing shared by the buffer types guards are made from.
+pub(crate) trait RangeSync {
+    fn prepare_read_range(&self, range: Range<usize>);
+
+    /// Write cpu stores back without handing the range to the device.
+    fn write_back_range(&self, range: Range<usize>);
+}
+
+/// Shared slice access to a DMA buffer.
+///
+/// The buffer is invalidated once when the guard is created, instead of on
+/// every element read like `get` and indexing do.
+pub struct DReadGuard<'a, T> {
+    slice: &'a [T],
+}
+
+impl<'a, T> DReadGuard<'a, T> {
+    pub(crate) fn new(sync: &dyn RangeSync, slice: &'a [T]) -> Self {
+        sync.prepare_read_range(0..slice.len());
+        Self { slice }
+    }
+}
+
+impl<T> Deref for DReadGuard<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.slice
+    }
+}
+
+/// Mutable slice access to a DMA buffer.
+///
+/// The buffer is invalidated once when the guard is created and flushed once
+/// when it is dropped. Only the ranges passed to [`DWriteGuard::mark_dirty`]
+/// are flushed if any were marked, otherwise the whole buffer is.
+pub struct DWriteGuard<'a, T> {
+    sync: &'a dyn RangeSync,
+    slice: &'a mut [T],
+    dirty: Option<Range<usize>>,
+}
+
+impl<'a, T> DWriteGuard<'a, T> {
+    pub(crate) fn new(sync: &'a dyn RangeSync, slice: &'a mut [T]) -> Self {
+        sync.prepare_read_range(0..slice.len());
+        Self {
+            sync,
+            slice,
+            dirty: None,
+        }
+    }
+
+    /// Limit the flush on drop to the marked elements, marks are merged into one span.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds.
+    pub fn mark_dirty(&mut self, range: impl RangeBounds<usize>) {
+        let range = elem_range(range, self.slice.len());
+        self.dirty = Some(match self.dirty.take() {
+            Some(d) => d.start.min(range.start)..d.end.max(range.end),
+            None => range,
+        });
+    }
+}
+
+impl<T> Deref for DWriteGuard<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.slice
+    }
+}
+
+impl<T> DerefMut for DWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.slice
+    }
+}
+
+impl<T> Drop for DWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let range = self.dirty.take().unwrap_or(0..self.slice.len());
+        let written = &self.slice[range.clone()];
+        if let Some(ptr) = core::ptr::NonNull::new(written.as_ptr() as *mut u8) {
+            cpu_wrote(ptr, size_of_val(written));
+        }
+        self.sync.write_back_range(range);
+    }
+}
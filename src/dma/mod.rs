@@ -1,19 +1,159 @@
-use crate::{flush, invalidate, Direction};
-use core::ptr::NonNull;
+use crate::{Direction, DmaDevice};
+use bounce::Bounce;
+use core::{
+    ops::{Bound, Range, RangeBounds},
+    ptr::NonNull,
+};
 
 #[cfg(feature = "alloc")]
 pub mod alloc;
+pub mod bounce;
+pub mod coherent;
+pub mod dir;
+pub mod guard;
 pub mod slice;
 
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum DError {
+    #[error("DMA mask not match, required {mask:#x}, got {got:#x}")]
+    DmaMaskNotMatch { mask: u64, got: u64 },
+    #[error("No memory")]
+    NoMemory,
+    #[error("Layout error")]
+    LayoutError,
+    #[error("Coherent allocation not supported")]
+    CoherentUnsupported,
+    #[error("Map failed")]
+    MapFailed,
+    #[error("Device writable {direction:?} mapping of a read-only buffer")]
+    ReadOnly { direction: Direction },
+    #[error("IOMMU address space exhausted")]
+    IommuExhausted,
+    #[error("DMA pool exhausted")]
+    PoolExhausted,
+    #[error("No size class fits {len:#x} bytes")]
+    NoSizeClass { len: usize },
+    #[error("Invalid IOVA range {iova:#x} size {size:#x}")]
+    InvalidIova { iova: u64, size: usize },
+    #[error("Buffer {addr:#x} size {size:#x} is not aligned to the {line:#x} byte cache line")]
+    Misaligned {
+        addr: usize,
+        size: usize,
+        line: usize,
+    },
+}
+
+impl From<core::alloc::LayoutError> for DError {
+    fn from(_: core::alloc::LayoutError) -> Self {
+        DError::LayoutError
+    }
+}
+
 impl Direction {
+    /// [`DmaDevice::sync_for_device`] on the platform set by [`crate::init`].
+    pub fn sync_for_device(self, ptr: NonNull<u8>, size: usize) {
+        DmaDevice::global(u64::MAX).sync_for_device(self, ptr, size);
+    }
+
+    /// [`DmaDevice::sync_for_cpu`] on the platform set by [`crate::init`].
+    pub fn sync_for_cpu(self, ptr: NonNull<u8>, size: usize) {
+        DmaDevice::global(u64::MAX).sync_for_cpu(self, ptr, size);
+    }
+
+    #[deprecated(note = "use `sync_for_cpu`")]
     pub fn prepare_read(self, ptr: NonNull<u8>, size: usize) {
-        if matches!(self, Direction::FromDevice | Direction::Bidirectional) {
-            invalidate(ptr, size);
-        }
+        self.sync_for_cpu(ptr, size);
     }
+
+    #[deprecated(note = "use `sync_for_device`")]
     pub fn confirm_write(self, ptr: NonNull<u8>, size: usize) {
-        if matches!(self, Direction::ToDevice | Direction::Bidirectional) {
-            flush(ptr, size)
-        }
+        self.sync_for_device(ptr, size);
     }
 }
+
+/// Tell the `dma-debug` registry the cpu wrote `ptr..ptr + size`.
+#[inline]
+pub(crate) fn cpu_wrote(ptr: NonNull<u8>, size: usize) {
+    #[cfg(feature = "dma-debug")]
+    crate::debug::on_cpu_write(ptr, size);
+    let _ = (ptr, size);
+}
+
+/// Tell the `dma-debug` registry a bounced `ptr..ptr + size` was handed to
+/// the device or back to the cpu, bounce copies skip [`DmaDevice`]'s syncs.
+#[inline]
+pub(crate) fn bounce_synced(ptr: NonNull<u8>, size: usize, to_device: bool) {
+    #[cfg(feature = "dma-debug")]
+    crate::debug::on_sync(
+        ptr,
+        size,
+        if to_device {
+            crate::debug::Owner::Device
+        } else {
+            crate::debug::Owner::Cpu
+        },
+    );
+    let _ = (ptr, size, to_device);
+}
+
+/// Resolve an element range against `len`, panicking like slice indexing when out of bounds.
+fn elem_range(range: impl RangeBounds<usize>, len: usize) -> Range<usize> {
+    let start = match range.start_bound() {
+        Bound::Included(&s) => s,
+        Bound::Excluded(&s) => s + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&e) => e + 1,
+        Bound::Excluded(&e) => e,
+        Bound::Unbounded => len,
+    };
+    assert!(
+        start <= end,
+        "range start {start} is greater than range end {end}"
+    );
+    assert!(end <= len, "range end {end} out of range for length {len}");
+    start..end
+}
+
+fn check_dma_mask(dma_mask: u64, bus_addr: u64) -> Result<(), DError> {
+    if (bus_addr) & (dma_mask) != (bus_addr) {
+        return Err(DError::DmaMaskNotMatch {
+            mask: dma_mask,
+            got: bus_addr,
+        });
+    }
+    Ok(())
+}
+
+/// Map `addr` and write its cache lines back, falling back to the bounce
+/// region if the bus address is outside the device's dma mask. The cpu owns
+/// the new mapping until it is handed over with `sync_for_device`.
+///
+/// Without `may_bounce` the mask miss is returned instead, bouncing copies
+/// device data back into the original buffer which must then be writable.
+fn map_with_mask(
+    dev: &DmaDevice,
+    addr: NonNull<u8>,
+    size: usize,
+    align: usize,
+    direction: Direction,
+    may_bounce: bool,
+) -> Result<(u64, Option<Bounce>), DError> {
+    let bus_addr = dev.map(addr, size, direction)?;
+    let Err(e) = check_dma_mask(dev.dma_mask(), bus_addr) else {
+        dev.clean_for_device(direction, addr, size);
+        return Ok((bus_addr, None));
+    };
+    dev.unmap(addr, size);
+    if !may_bounce {
+        return Err(e);
+    }
+
+    let bounce = Bounce::alloc(dev, addr, size, align).ok_or(e)?;
+    // still checked like a mapping, the bounce slot stands in for the memory
+    #[cfg(feature = "dma-debug")]
+    crate::debug::on_map(addr, size, direction);
+    bounce.fill(direction);
+    Ok((bounce.bus_addr(), Some(bounce)))
+}
@@ -1,18 +1,30 @@
 use core::{
     marker::PhantomData,
     mem::{size_of, size_of_val},
-    ops::Index,
+    ops::{Index, Range, RangeBounds},
     ptr::NonNull,
 };
 
-use crate::{flush, map, unmap, Direction};
+use crate::{
+    dma::{
+        bounce::Bounce,
+        bounce_synced, cpu_wrote,
+        dir::{DeviceReads, DeviceWrites, DmaDirection},
+        elem_range,
+        guard::{DReadGuard, DWriteGuard, RangeSync},
+        map_with_mask,
+    },
+    DError, Direction, DmaDevice,
+};
 
+/// A borrowed slice mapped for dma, directions work like [`crate::DVec`].
 #[repr(transparent)]
-pub struct DSlice<'a, T> {
+pub struct DSlice<'a, T, D = Direction> {
     inner: DSliceCommon<'a, T>,
+    _dir: PhantomData<D>,
 }
 
-impl<'a, T> DSlice<'a, T> {
+impl<'a, T, D: DmaDirection> DSlice<'a, T, D> {
     pub fn len(&self) -> usize {
         self.inner.len()
     }
@@ -21,26 +33,86 @@ impl<'a, T> DSlice<'a, T> {
         self.inner.bus_addr
     }
 
+    /// Whether the slice covers whole cache lines, so it shares none with other data.
+    pub fn is_cache_aligned(&self) -> bool {
+        self.inner
+            .dev
+            .is_cache_aligned(self.inner.addr.cast(), self.inner.size)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
-    pub fn from(value: &'a [T], direction: Direction) -> Self {
-        Self {
-            inner: DSliceCommon::new(value, direction),
-        }
+    /// # Panics
+    /// If mapping fails, see [`Self::try_from`].
+    pub fn from(value: &'a [T], direction: D) -> Self {
+        Self::try_from(value, direction).unwrap()
     }
 
-    pub fn prepare_read_all(&self) {
-        self.inner.prepare_read_all();
+    pub fn try_from(value: &'a [T], direction: D) -> Result<Self, DError> {
+        Self::from_with_mask(value, u64::MAX, direction)
     }
 
+    /// Map `value` for a device limited to `dma_mask`, bouncing it if needed.
+    pub fn from_with_mask(value: &'a [T], dma_mask: u64, direction: D) -> Result<Self, DError> {
+        Self::from_in(&DmaDevice::global(dma_mask), value, direction)
+    }
+
+    /// Map `value` for `dev`, bouncing it if it is outside the device's dma mask.
+    pub fn from_in(dev: &DmaDevice, value: &'a [T], direction: D) -> Result<Self, DError> {
+        Ok(Self {
+            inner: DSliceCommon::new(dev, value, direction.direction(), true)?,
+            _dir: PhantomData,
+        })
+    }
+
+    /// [`Self::sync_for_device`] on the whole buffer.
     pub fn confirm_write_all(&self) {
         self.inner.confirm_write_all();
     }
+
+    /// [`DmaDevice::sync_for_device`] on the elements in `range`.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds.
+    pub fn sync_for_device(&self, range: impl RangeBounds<usize>) {
+        self.inner.confirm_write_range(range);
+    }
+
+    #[deprecated(note = "use `sync_for_device`")]
+    pub fn confirm_write_range(&self, range: impl RangeBounds<usize>) {
+        self.sync_for_device(range);
+    }
+}
+
+impl<T, D: DeviceWrites> DSlice<'_, T, D> {
+    /// [`Self::sync_for_cpu`] on the whole buffer.
+    pub fn prepare_read_all(&self) {
+        self.inner.prepare_read_all();
+    }
+
+    /// [`DmaDevice::sync_for_cpu`] on the elements in `range`.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds.
+    pub fn sync_for_cpu(&self, range: impl RangeBounds<usize>) {
+        self.inner.prepare_read_range(range);
+    }
+
+    #[deprecated(note = "use `sync_for_cpu`")]
+    pub fn prepare_read_range(&self, range: impl RangeBounds<usize>) {
+        self.sync_for_cpu(range);
+    }
+
+    /// Borrow the buffer through a [`DReadGuard`].
+    pub fn read_guard(&self) -> DReadGuard<'_, T> {
+        let slice = unsafe { core::slice::from_raw_parts(self.inner.addr.as_ptr(), self.len()) };
+        DReadGuard::new(&self.inner, slice)
+    }
 }
 
-impl<T> Index<usize> for DSlice<'_, T> {
+impl<T, D: DeviceWrites> Index<usize> for DSlice<'_, T, D> {
     type Output = T;
 
     fn index(&self, index: usize) -> &Self::Output {
@@ -48,28 +120,54 @@ impl<T> Index<usize> for DSlice<'_, T> {
     }
 }
 
-impl<T> AsRef<[T]> for DSlice<'_, T> {
+impl<T, D: DeviceWrites> AsRef<[T]> for DSlice<'_, T, D> {
     fn as_ref(&self) -> &[T] {
         self.inner.as_ref()
     }
 }
 
+/// A mutably borrowed slice mapped for dma, directions work like [`crate::DVec`].
 #[repr(transparent)]
-pub struct DSliceMut<'a, T> {
+pub struct DSliceMut<'a, T, D = Direction> {
     inner: DSliceCommon<'a, T>,
+    _dir: PhantomData<D>,
 }
 
-impl<'a, T> DSliceMut<'a, T> {
-    pub fn from(value: &'a mut [T], direction: Direction) -> Self {
-        Self {
-            inner: DSliceCommon::new(value, direction),
-        }
+impl<'a, T, D: DmaDirection> DSliceMut<'a, T, D> {
+    /// # Panics
+    /// If mapping fails, see [`Self::try_from`].
+    pub fn from(value: &'a mut [T], direction: D) -> Self {
+        Self::try_from(value, direction).unwrap()
+    }
+
+    pub fn try_from(value: &'a mut [T], direction: D) -> Result<Self, DError> {
+        Self::from_with_mask(value, u64::MAX, direction)
+    }
+
+    /// Map `value` for a device limited to `dma_mask`, bouncing it if needed.
+    pub fn from_with_mask(value: &'a mut [T], dma_mask: u64, direction: D) -> Result<Self, DError> {
+        Self::from_in(&DmaDevice::global(dma_mask), value, direction)
+    }
+
+    /// Map `value` for `dev`, bouncing it if it is outside the device's dma mask.
+    pub fn from_in(dev: &DmaDevice, value: &'a mut [T], direction: D) -> Result<Self, DError> {
+        Ok(Self {
+            inner: DSliceCommon::new(dev, value, direction.direction(), false)?,
+            _dir: PhantomData,
+        })
     }
 
     pub fn bus_addr(&self) -> u64 {
         self.inner.bus_addr
     }
 
+    /// Whether the slice covers whole cache lines, so it shares none with other data.
+    pub fn is_cache_aligned(&self) -> bool {
+        self.inner
+            .dev
+            .is_cache_aligned(self.inner.addr.cast(), self.inner.size)
+    }
+
     pub fn len(&self) -> usize {
         self.inner.len()
     }
@@ -78,6 +176,52 @@ impl<'a, T> DSliceMut<'a, T> {
         self.len() == 0
     }
 
+    /// [`Self::sync_for_device`] on the whole buffer.
+    pub fn confirm_write_all(&self) {
+        self.inner.confirm_write_all();
+    }
+
+    /// [`DmaDevice::sync_for_device`] on the elements in `range`.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds.
+    pub fn sync_for_device(&self, range: impl RangeBounds<usize>) {
+        self.inner.confirm_write_range(range);
+    }
+
+    #[deprecated(note = "use `sync_for_device`")]
+    pub fn confirm_write_range(&self, range: impl RangeBounds<usize>) {
+        self.sync_for_device(range);
+    }
+}
+
+impl<T, D: DeviceWrites> DSliceMut<'_, T, D> {
+    /// [`Self::sync_for_cpu`] on the whole buffer.
+    pub fn prepare_read_all(&self) {
+        self.inner.prepare_read_all();
+    }
+
+    /// [`DmaDevice::sync_for_cpu`] on the elements in `range`.
+    ///
+    /// # Panics
+    /// If `range` is out of bounds.
+    pub fn sync_for_cpu(&self, range: impl RangeBounds<usize>) {
+        self.inner.prepare_read_range(range);
+    }
+
+    #[deprecated(note = "use `sync_for_cpu`")]
+    pub fn prepare_read_range(&self, range: impl RangeBounds<usize>) {
+        self.sync_for_cpu(range);
+    }
+
+    /// Borrow the buffer through a [`DReadGuard`].
+    pub fn read_guard(&self) -> DReadGuard<'_, T> {
+        let slice = unsafe { core::slice::from_raw_parts(self.inner.addr.as_ptr(), self.len()) };
+        DReadGuard::new(&self.inner, slice)
+    }
+}
+
+impl<T, D: DeviceReads> DSliceMut<'_, T, D> {
     pub fn set(&self, index: usize, value: T) {
         assert!(index < self.len());
 
@@ -86,22 +230,20 @@ impl<'a, T> DSliceMut<'a, T> {
 
             ptr.write_volatile(value);
 
-            self.inner
-                .direction
-                .confirm_write(ptr.cast(), size_of::<T>());
+            cpu_wrote(ptr.cast(), size_of::<T>());
+            self.inner.write_back(ptr.cast(), size_of::<T>());
         }
     }
 
-    pub fn prepare_read_all(&self) {
-        self.inner.prepare_read_all();
-    }
-
-    pub fn confirm_write_all(&self) {
-        self.inner.confirm_write_all();
+    /// Borrow the buffer mutably through a [`DWriteGuard`].
+    pub fn write_guard(&mut self) -> DWriteGuard<'_, T> {
+        let slice =
+            unsafe { core::slice::from_raw_parts_mut(self.inner.addr.as_ptr(), self.len()) };
+        DWriteGuard::new(&self.inner, slice)
     }
 }
 
-impl<T> Index<usize> for DSliceMut<'_, T> {
+impl<T, D: DeviceWrites> Index<usize> for DSliceMut<'_, T, D> {
     type Output = T;
 
     fn index(&self, index: usize) -> &Self::Output {
@@ -109,34 +251,89 @@ impl<T> Index<usize> for DSliceMut<'_, T> {
     }
 }
 
-impl<T> AsRef<[T]> for DSliceMut<'_, T> {
+impl<T, D: DeviceWrites> AsRef<[T]> for DSliceMut<'_, T, D> {
     fn as_ref(&self) -> &[T] {
         self.inner.as_ref()
     }
 }
 
 struct DSliceCommon<'a, T> {
+    dev: DmaDevice,
     addr: NonNull<T>,
     size: usize,
     bus_addr: u64,
     direction: Direction,
+    bounce: Option<Bounce>,
     _marker: PhantomData<&'a T>,
 }
 
 impl<'a, T> DSliceCommon<'a, T> {
-    fn new(s: &'a [T], direction: Direction) -> Self {
+    /// A `shared` slice is only bounced for [`Direction::ToDevice`], as the
+    /// device's writes would be copied back into memory borrowed immutably.
+    fn new(
+        dev: &DmaDevice,
+        s: &'a [T],
+        direction: Direction,
+        shared: bool,
+    ) -> Result<Self, DError> {
         let size = size_of_val(s);
         let ptr = unsafe { NonNull::new_unchecked(s.as_ptr() as usize as *mut T) };
-        let bus_addr = map(ptr.cast(), size, direction);
-
-        flush(ptr.cast(), size);
+        if dev.is_strict_alignment()
+            && matches!(direction, Direction::FromDevice | Direction::Bidirectional)
+            && !dev.is_cache_aligned(ptr.cast(), size)
+        {
+            return Err(DError::Misaligned {
+                addr: ptr.as_ptr() as usize,
+                size,
+                line: dev.cache_line_size(),
+            });
+        }
+        let may_bounce = !shared || direction == Direction::ToDevice;
+        let (bus_addr, bounce) = map_with_mask(
+            dev,
+            ptr.cast(),
+            size,
+            align_of::<T>(),
+            direction,
+            may_bounce,
+        )?;
 
-        Self {
+        Ok(Self {
+            dev: *dev,
             addr: ptr,
             size,
             bus_addr,
             direction,
+            bounce,
             _marker: PhantomData,
+        })
+    }
+
+    fn prepare_read(&self, ptr: NonNull<u8>, size: usize) {
+        match &self.bounce {
+            Some(bounce) => {
+                bounce_synced(ptr, size, false);
+                bounce.prepare_read(self.direction, ptr, size)
+            }
+            None => self.dev.sync_for_cpu(self.direction, ptr, size),
+        }
+    }
+
+    fn confirm_write(&self, ptr: NonNull<u8>, size: usize) {
+        match &self.bounce {
+            Some(bounce) => {
+                bounce_synced(ptr, size, true);
+                bounce.confirm_write(self.direction, ptr, size)
+            }
+            None => self.dev.sync_for_device(self.direction, ptr, size),
+        }
+    }
+
+    /// Make cpu writes visible to the device while the cpu keeps the buffer.
+    fn write_back(&self, ptr: NonNull<u8>, size: usize) {
+        match &self.bounce {
+            Some(bounce) => bounce.confirm_write(self.direction, ptr, size),
+            None => self.dev.clean_for_device(self.direction, ptr, size),
         }
     }
 
@@ -149,25 +346,58 @@ impl<'a, T> DSliceCommon<'a, T> {
 
         let ptr = unsafe { self.addr.add(index) };
 
-        self.direction.prepare_read(ptr.cast(), size_of::<T>());
+        self.prepare_read(ptr.cast(), size_of::<T>());
 
         unsafe { ptr.as_ref() }
     }
 
     fn prepare_read_all(&self) {
-        self.direction
-            .prepare_read(self.addr.cast(), self.size * size_of::<T>());
+        self.prepare_read(self.addr.cast(), self.size);
     }
 
     fn confirm_write_all(&self) {
-        self.direction
-            .confirm_write(self.addr.cast(), self.size * size_of::<T>());
+        self.confirm_write(self.addr.cast(), self.size);
+    }
+
+    fn prepare_read_range(&self, range: impl RangeBounds<usize>) {
+        let range = elem_range(range, self.len());
+        let ptr = unsafe { self.addr.add(range.start) };
+        self.prepare_read(ptr.cast(), range.len() * size_of::<T>());
+    }
+
+    fn confirm_write_range(&self, range: impl RangeBounds<usize>) {
+        let range = elem_range(range, self.len());
+        let ptr = unsafe { self.addr.add(range.start) };
+        self.confirm_write(ptr.cast(), range.len() * size_of::<T>());
+    }
+
+    fn write_back_range(&self, range: impl RangeBounds<usize>) {
+        let range = elem_range(range, self.len());
+        let ptr = unsafe { self.addr.add(range.start) };
+        self.write_back(ptr.cast(), range.len() * size_of::<T>());
+    }
+}
+
+impl<T> RangeSync for DSliceCommon<'_, T> {
+    fn prepare_read_range(&self, range: Range<usize>) {
+        DSliceCommon::prepare_read_range(self, range);
+    }
+
+    fn write_back_range(&self, range: Range<usize>) {
+        DSliceCommon::write_back_range(self, range);
     }
 }
 
 impl<T> Drop for DSliceCommon<'_, T> {
     fn drop(&mut self) {
-        unmap(self.addr.cast(), self.size);
+        match &self.bounce {
+            // like swiotlb, hand what the device wrote back to the original buffer
+            Some(bounce) => {
+                bounce_synced(self.addr.cast(), self.size, false);
+                bounce.prepare_read(self.direction, self.addr.cast(), self.size)
+            }
+            None => self.dev.unmap(self.addr.cast(), self.size),
+        }
     }
 }
 
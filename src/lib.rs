@@ -6,13 +6,40 @@ extern crate alloc;
 
 use core::{ptr::NonNull, sync::atomic::AtomicBool};
 
+#[cfg(feature = "dma-debug")]
+pub mod debug;
+mod device;
 mod dma;
 mod osal;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-#[cfg(feature = "alloc")]
-pub use dma::alloc::{pool::*, r#box::DBox, vec::DVec, DError};
+pub use device::DmaDevice;
+
+#[cfg(target_arch = "riscv64")]
+pub use osal::arch::set_cache_line_size;
 
-pub use dma::slice::{DSlice, DSliceMut};
+#[cfg(feature = "alloc")]
+pub use dma::alloc::{
+    io_pgtable::{IoPageTable, IoProt, LpaeStage},
+    iova::IovaAllocator,
+    pool::*,
+    r#box::DBox,
+    ring::DRing,
+    sg::{DSgList, DSgSegments},
+    size_class::{DSizeClass, DSizeClassPool, DSizeClassStats},
+    transfer::{DCompleter, DTransfer, DmaBuffer},
+    vec::DVec,
+};
+
+pub use dma::{
+    bounce::{BounceRegion, BounceStats, BOUNCE_SLOT_SIZE},
+    coherent::{DCoherentBox, DCoherentVec},
+    dir::{Bidirectional, DeviceReads, DeviceWrites, DmaDirection, FromDevice, ToDevice},
+    guard::{DReadGuard, DWriteGuard},
+    slice::{DSlice, DSliceMut},
+    DError,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
@@ -22,10 +49,36 @@ pub enum Direction {
     Bidirectional,
 }
 
-pub trait Osal {
+/// Memory returned by [`Osal::alloc_coherent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoherentMem {
+    /// uncached cpu address
+    pub cpu_addr: NonNull<u8>,
+    /// address the device uses
+    pub bus_addr: u64,
+}
+
+/// Shared by every thread that maps memory, so it must be `Sync`.
+pub trait Osal: Sync {
     /// map virt address to physical address
     fn map(&self, addr: NonNull<u8>, size: usize, direction: Direction) -> u64;
 
+    /// map virt address to physical address, reporting failures like IOMMU exhaustion
+    ///
+    /// `dma_mask` is the mask of the mapping device, an IOMMU implementation
+    /// passes it to `IovaAllocator::alloc`. The default calls `map`, which
+    /// can't fail.
+    fn try_map(
+        &self,
+        addr: NonNull<u8>,
+        size: usize,
+        direction: Direction,
+        dma_mask: u64,
+    ) -> Result<u64, DError> {
+        let _ = dma_mask;
+        Ok(self.map(addr, size, direction))
+    }
+
     /// unmap virt address
     fn unmap(&self, addr: NonNull<u8>, size: usize);
 
@@ -39,6 +92,79 @@ pub trait Osal {
         osal::arch::invalidate(addr, size)
     }
 
+    /// write cache back to memory and invalidate it, so no dirty line is evicted over later device writes
+    ///
+    /// The default is the combined operation of the architecture, such as
+    /// `dc civac`, and doesn't go through `flush` or `invalidate`. An `Osal`
+    /// overriding those has to override this as well.
+    fn flush_invalidate(&self, addr: NonNull<u8>, size: usize) {
+        osal::arch::flush_invalidate(addr, size)
+    }
+
+    /// order cpu writes to dma memory before later writes, e.g. before ringing a doorbell
+    fn dma_wmb(&self) {
+        osal::arch::dma_wmb()
+    }
+
+    /// order earlier reads, e.g. of a completion flag, before later reads of dma memory
+    fn dma_rmb(&self) {
+        osal::arch::dma_rmb()
+    }
+
+    /// full barrier between dma memory accesses
+    fn dma_mb(&self) {
+        osal::arch::dma_mb()
+    }
+
+    /// size of the cache lines `flush` and `invalidate` operate on
+    ///
+    /// riscv64 has no way to discover it, override this with the
+    /// `riscv,cbom-block-size` device tree property there. [`init`] and
+    /// [`DmaDevice::new`] pass it on to the default `flush` and `invalidate`.
+    fn cache_line_size(&self) -> usize {
+        osal::arch::cache_line_size()
+    }
+
+    /// whether devices snoop the cpu caches, if so streaming mappings skip flush and invalidate
+    ///
+    /// The default is true on x86_64, where PCIe DMA snoops, and false elsewhere.
+    fn is_coherent(&self) -> bool {
+        osal::arch::is_coherent()
+    }
+
+    /// the region to bounce mappings outside a device's dma mask into, `None` fails them instead
+    fn bounce_region(&self) -> Option<&BounceRegion> {
+        None
+    }
+
+    /// index of the running cpu, used to pick per-cpu caches such as `DVecPool` magazines
+    ///
+    /// The default is always 0, so per-cpu caches need an override.
+    fn cpu_id(&self) -> usize {
+        0
+    }
+
+    /// allocate zeroed memory mapped uncached for the device, `None` if the platform can't
+    ///
+    /// # Safety
+    /// The caller must release the memory with `dealloc_coherent` using the same `layout`.
+    unsafe fn alloc_coherent(
+        &self,
+        dma_mask: u64,
+        layout: core::alloc::Layout,
+    ) -> Option<CoherentMem> {
+        let _ = (dma_mask, layout);
+        None
+    }
+
+    /// release memory from `alloc_coherent`
+    ///
+    /// # Safety
+    /// `mem` must come from `alloc_coherent` with the same `layout`, and not be accessed afterwards.
+    unsafe fn dealloc_coherent(&self, mem: CoherentMem, layout: core::alloc::Layout) {
+        let _ = (mem, layout);
+    }
+
     /// allocate memory that meets the dma requirement
     ///
     /// # Safety
@@ -73,6 +199,7 @@ pub fn init(osal: &'static dyn Osal) {
         return;
     }
 
+    osal::cache_line_size(osal);
     unsafe {
         OSAL = osal;
     }
@@ -86,28 +213,27 @@ fn get_osal() -> &'static dyn Osal {
     unsafe { OSAL }
 }
 
-fn map(addr: NonNull<u8>, size: usize, direction: Direction) -> u64 {
-    get_osal().map(addr, size, direction)
+/// [`Osal::dma_wmb`] of the platform set by [`init`].
+pub fn dma_wmb() {
+    get_osal().dma_wmb()
 }
 
-fn unmap(addr: NonNull<u8>, size: usize) {
-    get_osal().unmap(addr, size)
+/// [`Osal::dma_rmb`] of the platform set by [`init`].
+pub fn dma_rmb() {
+    get_osal().dma_rmb()
 }
 
-fn invalidate(addr: NonNull<u8>, size: usize) {
-    get_osal().invalidate(addr, size)
+/// [`Osal::dma_mb`] of the platform set by [`init`].
+pub fn dma_mb() {
+    get_osal().dma_mb()
 }
 
-fn flush(addr: NonNull<u8>, size: usize) {
-    get_osal().flush(addr, size)
+/// [`Osal::cache_line_size`] of the platform set by [`init`].
+pub fn cache_line_size() -> usize {
+    get_osal().cache_line_size()
 }
 
-#[cfg(feature = "alloc")]
-fn alloc(dma_mask: u64, layout: core::alloc::Layout) -> *mut u8 {
-    unsafe { get_osal().alloc(dma_mask, layout) }
-}
-
-#[cfg(feature = "alloc")]
-fn dealloc(ptr: *mut u8, layout: core::alloc::Layout) {
-    unsafe { get_osal().dealloc(ptr, layout) }
+/// Whether the platform set by [`init`] is dma coherent.
+pub fn is_coherent() -> bool {
+    get_osal().is_coherent()
 }
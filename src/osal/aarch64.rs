@@ -1,11 +1,50 @@
-use core::ptr::NonNull;
+use core::{arch::asm, ptr::NonNull};
 
 use aarch64_cpu_ext::cache::{dcache_range, CacheOp};
 
+// `dc` operations only complete at a `dsb`, don't rely on `dcache_range` issuing one.
+
 pub fn flush(addr: NonNull<u8>, size: usize) {
     dcache_range(CacheOp::Clean, addr.as_ptr() as _, size);
+    unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
 }
 
 pub fn invalidate(addr: NonNull<u8>, size: usize) {
     dcache_range(CacheOp::Invalidate, addr.as_ptr() as _, size);
+    unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
+}
+
+/// `dc civac` by hand, `dcache_range` has no clean and invalidate operation.
+pub fn flush_invalidate(addr: NonNull<u8>, size: usize) {
+    let line = cache_line_size();
+    let end = addr.as_ptr() as usize + size;
+    let mut addr = addr.as_ptr() as usize & !(line - 1);
+    while addr < end {
+        unsafe { asm!("dc civac, {0}", in(reg) addr, options(nostack, preserves_flags)) };
+        addr += line;
+    }
+    unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
+}
+
+/// Smallest data cache line, from CTR_EL0.DminLine in words.
+pub fn cache_line_size() -> usize {
+    let ctr: u64;
+    unsafe { asm!("mrs {0}, ctr_el0", out(reg) ctr, options(nomem, nostack, preserves_flags)) };
+    4 << ((ctr >> 16) & 0xf)
+}
+
+pub fn is_coherent() -> bool {
+    false
+}
+
+pub fn dma_wmb() {
+    unsafe { asm!("dmb oshst", options(nostack, preserves_flags)) };
+}
+
+pub fn dma_rmb() {
+    unsafe { asm!("dmb oshld", options(nostack, preserves_flags)) };
+}
+
+pub fn dma_mb() {
+    unsafe { asm!("dmb osh", options(nostack, preserves_flags)) };
 }
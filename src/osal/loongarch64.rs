@@ -0,0 +1,74 @@
+use core::{
+    arch::asm,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static CACHE_LINE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+fn cpucfg(word: usize) -> usize {
+    let value;
+    unsafe { asm!("cpucfg {0}, {1}", out(reg) value, in(reg) word, options(nomem, nostack)) };
+    value
+}
+
+/// Line size of the L1 data cache, from CPUCFG word 0x12 bits [30:24].
+pub fn cache_line_size() -> usize {
+    let line = CACHE_LINE_SIZE.load(Ordering::Relaxed);
+    if line != 0 {
+        return line;
+    }
+    let line = 1 << ((cpucfg(0x12) >> 24) & 0x7f);
+    CACHE_LINE_SIZE.store(line, Ordering::Relaxed);
+    line
+}
+
+fn dbar() {
+    unsafe { asm!("dbar 0", options(nostack, preserves_flags)) };
+}
+
+pub fn is_coherent() -> bool {
+    false
+}
+
+pub fn dma_wmb() {
+    dbar();
+}
+
+pub fn dma_rmb() {
+    dbar();
+}
+
+pub fn dma_mb() {
+    dbar();
+}
+
+/// `cacop` hit writeback-invalidate on the L1 data cache, Loongson keeps the
+/// shared levels coherent with DMA.
+///
+/// LoongArch has no clean-only or drop-only hit operation, so `flush` and
+/// `invalidate` do the same.
+fn writeback_invalidate(addr: NonNull<u8>, size: usize) {
+    let line = cache_line_size();
+    let end = addr.as_ptr() as usize + size;
+    let mut addr = addr.as_ptr() as usize & !(line - 1);
+
+    dbar();
+    while addr < end {
+        unsafe { asm!("cacop 0x11, {0}, 0", in(reg) addr, options(nostack, preserves_flags)) };
+        addr += line;
+    }
+    dbar();
+}
+
+pub fn flush(addr: NonNull<u8>, size: usize) {
+    writeback_invalidate(addr, size);
+}
+
+pub fn invalidate(addr: NonNull<u8>, size: usize) {
+    writeback_invalidate(addr, size);
+}
+
+pub fn flush_invalidate(addr: NonNull<u8>, size: usize) {
+    writeback_invalidate(addr, size);
+}
@@ -4,12 +4,34 @@ cfg_if::cfg_if! {
     if #[cfg(target_arch = "aarch64")] {
         #[path = "aarch64.rs"]
         pub mod arch;
+    } else if #[cfg(target_arch = "riscv64")] {
+        #[path = "riscv64.rs"]
+        pub mod arch;
+    } else if #[cfg(target_arch = "x86_64")] {
+        #[path = "x86_64.rs"]
+        pub mod arch;
+    } else if #[cfg(target_arch = "loongarch64")] {
+        #[path = "loongarch64.rs"]
+        pub mod arch;
     } else{
         #[path = "nop.rs"]
         pub mod arch;
     }
 }
 
+/// The line size of `osal`, also handed to the arch cache operations on
+/// riscv64, where only the device tree knows it.
+pub(crate) fn cache_line_size(osal: &dyn Osal) -> usize {
+    let line = osal.cache_line_size();
+    assert!(
+        line.is_power_of_two(),
+        "cache line size must be a power of two"
+    );
+    #[cfg(target_arch = "riscv64")]
+    arch::set_cache_line_size(line);
+    line
+}
+
 pub struct NopOsal;
 
 #[allow(unused_variables)]
@@ -1,5 +1,30 @@
-use core::ptr::NonNull;
+use core::{
+    ptr::NonNull,
+    sync::atomic::{fence, Ordering},
+};
+
+pub fn cache_line_size() -> usize {
+    64
+}
 
 pub fn flush(_addr: NonNull<u8>, _size: usize) {}
 
 pub fn invalidate(_addr: NonNull<u8>, _size: usize) {}
+
+pub fn flush_invalidate(_addr: NonNull<u8>, _size: usize) {}
+
+pub fn is_coherent() -> bool {
+    false
+}
+
+pub fn dma_wmb() {
+    fence(Ordering::Release);
+}
+
+pub fn dma_rmb() {
+    fence(Ordering::Acquire);
+}
+
+pub fn dma_mb() {
+    fence(Ordering::SeqCst);
+}
@@ -0,0 +1,125 @@
+use core::{
+    arch::asm,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Zicbom has no CSR for the cache block size, 64 is what the C906/C910 and JH7110 use
+/// until an `Osal` reports another one.
+static CACHE_LINE_SIZE: AtomicUsize = AtomicUsize::new(64);
+
+/// Set the cache block size, usually from the `riscv,cbom-block-size` device tree property.
+pub fn set_cache_line_size(size: usize) {
+    assert!(
+        size.is_power_of_two(),
+        "cache line size must be a power of two"
+    );
+    CACHE_LINE_SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn cache_line_size() -> usize {
+    CACHE_LINE_SIZE.load(Ordering::Relaxed)
+}
+
+fn for_each_line(addr: NonNull<u8>, size: usize, op: impl Fn(usize)) {
+    let line = cache_line_size();
+    let end = addr.as_ptr() as usize + size;
+    let mut addr = addr.as_ptr() as usize & !(line - 1);
+    while addr < end {
+        op(addr);
+        addr += line;
+    }
+}
+
+#[cfg(not(feature = "thead"))]
+mod op {
+    use core::arch::asm;
+
+    // Encoded with `.insn` so no `zicbom` target feature is needed:
+    // cbo.inval = imm 0, cbo.clean = imm 1, cbo.flush = imm 2.
+
+    pub fn clean(addr: usize) {
+        unsafe { asm!(".insn i 0x0F, 2, x0, {0}, 1", in(reg) addr) };
+    }
+
+    pub fn inval(addr: usize) {
+        unsafe { asm!(".insn i 0x0F, 2, x0, {0}, 0", in(reg) addr) };
+    }
+
+    pub fn clean_inval(addr: usize) {
+        unsafe { asm!(".insn i 0x0F, 2, x0, {0}, 2", in(reg) addr) };
+    }
+
+    pub fn sync() {
+        unsafe { asm!("fence rw, rw") };
+    }
+}
+
+/// T-Head XTheadCmo, for the C906/C910 which predate Zicbom.
+#[cfg(feature = "thead")]
+mod op {
+    use core::arch::asm;
+
+    pub fn clean(addr: usize) {
+        // th.dcache.cva a0
+        unsafe { asm!(".long 0x0255000b", in("a0") addr) };
+    }
+
+    pub fn inval(addr: usize) {
+        // th.dcache.iva a0
+        unsafe { asm!(".long 0x0265000b", in("a0") addr) };
+    }
+
+    pub fn clean_inval(addr: usize) {
+        // th.dcache.civa a0
+        unsafe { asm!(".long 0x0275000b", in("a0") addr) };
+    }
+
+    pub fn sync() {
+        // th.sync.s
+        unsafe { asm!(".long 0x0190000b") };
+    }
+}
+
+pub fn is_coherent() -> bool {
+    false
+}
+
+pub fn dma_wmb() {
+    unsafe { asm!("fence w, w", options(nostack)) };
+}
+
+pub fn dma_rmb() {
+    unsafe { asm!("fence r, r", options(nostack)) };
+}
+
+pub fn dma_mb() {
+    unsafe { asm!("fence rw, rw", options(nostack)) };
+}
+
+pub fn flush(addr: NonNull<u8>, size: usize) {
+    unsafe { asm!("fence rw, rw") };
+    for_each_line(addr, size, op::clean);
+    op::sync();
+}
+
+pub fn invalidate(addr: NonNull<u8>, size: usize) {
+    // lines only partly inside the range may hold cpu writes, don't drop them
+    let line = cache_line_size();
+    let start = addr.as_ptr() as usize;
+    let end = start + size;
+    for_each_line(addr, size, |a| {
+        if a < start || a + line > end {
+            op::clean_inval(a)
+        } else {
+            op::inval(a)
+        }
+    });
+    op::sync();
+}
+
+pub fn flush_invalidate(addr: NonNull<u8>, size: usize) {
+    unsafe { asm!("fence rw, rw") };
+    for_each_line(addr, size, op::clean_inval);
+    op::sync();
+}
@@ -0,0 +1,121 @@
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count, _mm_clflush},
+    },
+    ptr::NonNull,
+    sync::atomic::{compiler_fence, AtomicU8, AtomicUsize, Ordering},
+};
+
+const DETECTED: u8 = 1;
+const CLFLUSHOPT: u8 = 1 << 1;
+const CLWB: u8 = 1 << 2;
+
+static FEATURES: AtomicU8 = AtomicU8::new(0);
+static CACHE_LINE_SIZE: AtomicUsize = AtomicUsize::new(64);
+
+fn features() -> u8 {
+    let features = FEATURES.load(Ordering::Acquire);
+    if features & DETECTED != 0 {
+        return features;
+    }
+
+    // CPUID.01H:EBX[15:8] is the clflush line size in 8 byte units
+    let line = ((__cpuid(1).ebx >> 8) & 0xff) as usize * 8;
+    if line != 0 {
+        CACHE_LINE_SIZE.store(line, Ordering::Relaxed);
+    }
+
+    let mut features = DETECTED;
+    if __cpuid(0).eax >= 7 {
+        let ebx = __cpuid_count(7, 0).ebx;
+        if ebx & (1 << 23) != 0 {
+            features |= CLFLUSHOPT;
+        }
+        if ebx & (1 << 24) != 0 {
+            features |= CLWB;
+        }
+    }
+    FEATURES.store(features, Ordering::Release);
+    features
+}
+
+pub fn cache_line_size() -> usize {
+    features();
+    CACHE_LINE_SIZE.load(Ordering::Relaxed)
+}
+
+fn for_each_line(addr: NonNull<u8>, size: usize, op: impl Fn(*const u8)) {
+    let line = cache_line_size();
+    let end = addr.as_ptr() as usize + size;
+    let mut addr = addr.as_ptr() as usize & !(line - 1);
+    while addr < end {
+        op(addr as *const u8);
+        addr += line;
+    }
+}
+
+fn clflushopt(p: *const u8) {
+    unsafe { asm!("clflushopt [{0}]", in(reg) p, options(nostack, preserves_flags)) };
+}
+
+fn clwb(p: *const u8) {
+    unsafe { asm!("clwb [{0}]", in(reg) p, options(nostack, preserves_flags)) };
+}
+
+fn clflush(p: *const u8) {
+    unsafe { _mm_clflush(p) };
+}
+
+fn mfence() {
+    unsafe { asm!("mfence", options(nostack, preserves_flags)) };
+}
+
+/// PCIe DMA snoops the cpu caches on x86, `flush` and `invalidate` are only for
+/// devices behind a non-snooping path, which opt in with `Osal::is_coherent`.
+pub fn is_coherent() -> bool {
+    true
+}
+
+/// x86 doesn't reorder stores with stores, only the compiler has to be stopped.
+pub fn dma_wmb() {
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// x86 doesn't reorder loads with loads, only the compiler has to be stopped.
+pub fn dma_rmb() {
+    compiler_fence(Ordering::SeqCst);
+}
+
+pub fn dma_mb() {
+    mfence();
+}
+
+/// Write back lines for devices that don't snoop, `clwb` keeps them cached.
+pub fn flush(addr: NonNull<u8>, size: usize) {
+    let features = features();
+    if features & CLWB != 0 {
+        for_each_line(addr, size, clwb);
+    } else if features & CLFLUSHOPT != 0 {
+        for_each_line(addr, size, clflushopt);
+    } else {
+        for_each_line(addr, size, clflush);
+    }
+    mfence();
+}
+
+/// x86 can't drop a line without writing it back, so this flushes and evicts.
+pub fn invalidate(addr: NonNull<u8>, size: usize) {
+    mfence();
+    if features() & CLFLUSHOPT != 0 {
+        for_each_line(addr, size, clflushopt);
+    } else {
+        for_each_line(addr, size, clflush);
+    }
+    mfence();
+}
+
+/// `clflush` always writes back and evicts, the same as `invalidate`.
+pub fn flush_invalidate(addr: NonNull<u8>, size: usize) {
+    invalidate(addr, size);
+}
@@ -0,0 +1,495 @@
+//! Osal implementations for driver unit tests.
+
+use alloc::{vec, vec::Vec};
+use core::{alloc::Layout, ptr::NonNull};
+
+use spin::Mutex;
+
+use crate::{Direction, Osal};
+
+/// One call seen by [`RecordingOsal`], addresses are cpu addresses unless named `bus_addr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Map {
+        addr: usize,
+        size: usize,
+        direction: Direction,
+        bus_addr: u64,
+    },
+    Unmap {
+        addr: usize,
+        size: usize,
+    },
+    Flush {
+        addr: usize,
+        size: usize,
+    },
+    Invalidate {
+        addr: usize,
+        size: usize,
+    },
+    FlushInvalidate {
+        addr: usize,
+        size: usize,
+    },
+    Alloc {
+        addr: usize,
+        size: usize,
+        dma_mask: u64,
+    },
+    Dealloc {
+        addr: usize,
+        size: usize,
+    },
+    /// the simulated device read the buffer, see [`RecordingOsal::device_read`]
+    DeviceRead {
+        addr: usize,
+        size: usize,
+        bus_addr: u64,
+    },
+    /// the simulated device wrote the buffer, see [`RecordingOsal::device_write`]
+    DeviceWrite {
+        addr: usize,
+        size: usize,
+        bus_addr: u64,
+    },
+}
+
+/// Barriers seen by [`RecordingOsal`], counted rather than recorded as events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Barriers {
+    pub wmb: usize,
+    pub rmb: usize,
+    pub mb: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    addr: usize,
+    size: usize,
+    bus_addr: u64,
+}
+
+const PAGE: u64 = 0x1000;
+
+/// An `Osal` that records every call and translates through a fake IOMMU.
+///
+/// Bus addresses are handed out from `iova_base` upwards and keep only the
+/// offset inside the page, so a driver giving the device a cpu pointer is
+/// caught by [`RecordingOsal::device_read`] and friends.
+pub struct RecordingOsal {
+    iova_base: u64,
+    cache_line: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    next_iova: u64,
+    events: Vec<Event>,
+    mappings: Vec<Mapping>,
+    barriers: Barriers,
+}
+
+impl Default for RecordingOsal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecordingOsal {
+    /// IOVAs start at 256 MiB, inside a 32 bit dma mask.
+    pub const fn new() -> Self {
+        Self::with_iova_base(0x1000_0000)
+    }
+
+    pub const fn with_iova_base(iova_base: u64) -> Self {
+        Self {
+            iova_base,
+            cache_line: 64,
+            inner: Mutex::new(Inner {
+                next_iova: iova_base,
+                events: Vec::new(),
+                mappings: Vec::new(),
+                barriers: Barriers {
+                    wmb: 0,
+                    rmb: 0,
+                    mb: 0,
+                },
+            }),
+        }
+    }
+
+    /// Report `size` from [`Osal::cache_line_size`] instead of 64.
+    pub const fn with_cache_line_size(mut self, size: usize) -> Self {
+        self.cache_line = size;
+        self
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.inner.lock().events.clone()
+    }
+
+    pub fn clear_events(&self) {
+        self.inner.lock().events.clear();
+    }
+
+    pub fn barriers(&self) -> Barriers {
+        self.inner.lock().barriers
+    }
+
+    /// First address handed out, nothing below is ever a valid bus address.
+    pub fn iova_base(&self) -> u64 {
+        self.iova_base
+    }
+
+    /// Cpu address behind `bus_addr..bus_addr + size`.
+    ///
+    /// # Panics
+    /// If the range is not inside one live mapping.
+    pub fn bus_to_cpu(&self, bus_addr: u64, size: usize) -> NonNull<u8> {
+        let inner = self.inner.lock();
+        let m = inner
+            .mappings
+            .iter()
+            .find(|m| {
+                bus_addr >= m.bus_addr && bus_addr + size as u64 <= m.bus_addr + m.size as u64
+            })
+            .unwrap_or_else(|| panic!("{bus_addr:#x} size {size:#x} is not a mapped bus address"));
+        NonNull::new((m.addr as u64 + (bus_addr - m.bus_addr)) as usize as *mut u8).unwrap()
+    }
+
+    /// Act as the device reading `buf.len()` bytes at `bus_addr`.
+    pub fn device_read(&self, bus_addr: u64, buf: &mut [u8]) {
+        let addr = self.bus_to_cpu(bus_addr, buf.len());
+        unsafe { core::ptr::copy_nonoverlapping(addr.as_ptr(), buf.as_mut_ptr(), buf.len()) };
+        self.record(Event::DeviceRead {
+            addr: addr.as_ptr() as usize,
+            size: buf.len(),
+            bus_addr,
+        });
+    }
+
+    /// Act as the device writing `data` at `bus_addr`.
+    pub fn device_write(&self, bus_addr: u64, data: &[u8]) {
+        let addr = self.bus_to_cpu(bus_addr, data.len());
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), addr.as_ptr(), data.len()) };
+        self.record(Event::DeviceWrite {
+            addr: addr.as_ptr() as usize,
+            size: data.len(),
+            bus_addr,
+        });
+    }
+
+    /// Assert `addr..addr + size` was flushed before the device last read `bus_addr`,
+    /// [`Event::FlushInvalidate`] counts as a flush.
+    pub fn assert_flushed_before_read(&self, addr: NonNull<u8>, size: usize, bus_addr: u64) {
+        let events = self.events();
+        let read = events
+            .iter()
+            .rposition(|e| matches!(e, Event::DeviceRead { bus_addr: b, .. } if *b == bus_addr))
+            .unwrap_or_else(|| panic!("device never read {bus_addr:#x}"));
+        let addr = addr.as_ptr() as usize;
+        assert!(
+            events[..read].iter().any(
+                |e| matches!(*e, Event::Flush { addr: a, size: s } | Event::FlushInvalidate { addr: a, size: s } if covers(a, s, addr, size))
+            ),
+            "{addr:#x} size {size:#x} was not flushed before the device read {bus_addr:#x}"
+        );
+    }
+
+    /// Assert `addr..addr + size` was invalidated after the device last wrote `bus_addr`,
+    /// [`Event::FlushInvalidate`] counts as an invalidate.
+    pub fn assert_invalidated_after_write(&self, addr: NonNull<u8>, size: usize, bus_addr: u64) {
+        let events = self.events();
+        let write = events
+            .iter()
+            .rposition(|e| matches!(e, Event::DeviceWrite { bus_addr: b, .. } if *b == bus_addr))
+            .unwrap_or_else(|| panic!("device never wrote {bus_addr:#x}"));
+        let addr = addr.as_ptr() as usize;
+        assert!(
+            events[write..].iter().any(
+                |e| matches!(*e, Event::Invalidate { addr: a, size: s } | Event::FlushInvalidate { addr: a, size: s } if covers(a, s, addr, size))
+            ),
+            "{addr:#x} size {size:#x} was not invalidated after the device wrote {bus_addr:#x}"
+        );
+    }
+
+    pub fn assert_no_live_mappings(&self) {
+        let inner = self.inner.lock();
+        assert!(
+            inner.mappings.is_empty(),
+            "live mappings: {:x?}",
+            inner.mappings
+        );
+    }
+
+    fn record(&self, event: Event) {
+        self.inner.lock().events.push(event);
+    }
+}
+
+/// Hand out page granular IOVAs that keep the offset of `addr` inside its page.
+fn alloc_iova(next: &mut u64, addr: usize, size: usize) -> u64 {
+    let offset = addr as u64 % PAGE;
+    let bus_addr = *next + offset;
+    *next += (offset + size as u64).div_ceil(PAGE).max(1) * PAGE;
+    bus_addr
+}
+
+fn covers(addr: usize, size: usize, inner: usize, inner_size: usize) -> bool {
+    addr <= inner && inner + inner_size <= addr + size
+}
+
+impl Osal for RecordingOsal {
+    fn map(&self, addr: NonNull<u8>, size: usize, direction: Direction) -> u64 {
+        let addr = addr.as_ptr() as usize;
+        let mut inner = self.inner.lock();
+
+        let bus_addr = alloc_iova(&mut inner.next_iova, addr, size);
+        inner.mappings.push(Mapping {
+            addr,
+            size,
+            bus_addr,
+        });
+        inner.events.push(Event::Map {
+            addr,
+            size,
+            direction,
+            bus_addr,
+        });
+        bus_addr
+    }
+
+    fn unmap(&self, addr: NonNull<u8>, size: usize) {
+        let addr = addr.as_ptr() as usize;
+        let mut inner = self.inner.lock();
+        let i = inner
+            .mappings
+            .iter()
+            .position(|m| m.addr == addr && m.size == size)
+            .unwrap_or_else(|| panic!("unmap of {addr:#x} size {size:#x} which is not mapped"));
+        inner.mappings.swap_remove(i);
+        inner.events.push(Event::Unmap { addr, size });
+    }
+
+    fn flush(&self, addr: NonNull<u8>, size: usize) {
+        self.record(Event::Flush {
+            addr: addr.as_ptr() as usize,
+            size,
+        });
+    }
+
+    fn invalidate(&self, addr: NonNull<u8>, size: usize) {
+        self.record(Event::Invalidate {
+            addr: addr.as_ptr() as usize,
+            size,
+        });
+    }
+
+    fn flush_invalidate(&self, addr: NonNull<u8>, size: usize) {
+        self.record(Event::FlushInvalidate {
+            addr: addr.as_ptr() as usize,
+            size,
+        });
+    }
+
+    fn dma_wmb(&self) {
+        self.inner.lock().barriers.wmb += 1;
+    }
+
+    fn dma_rmb(&self) {
+        self.inner.lock().barriers.rmb += 1;
+    }
+
+    fn dma_mb(&self) {
+        self.inner.lock().barriers.mb += 1;
+    }
+
+    fn cache_line_size(&self) -> usize {
+        self.cache_line
+    }
+
+    fn is_coherent(&self) -> bool {
+        false
+    }
+
+    unsafe fn alloc(&self, dma_mask: u64, layout: Layout) -> *mut u8 {
+        let ptr = alloc::alloc::alloc(layout);
+        self.record(Event::Alloc {
+            addr: ptr as usize,
+            size: layout.size(),
+            dma_mask,
+        });
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.record(Event::Dealloc {
+            addr: ptr as usize,
+            size: layout.size(),
+        });
+        alloc::alloc::dealloc(ptr, layout)
+    }
+}
+
+const SIM_LINE: usize = 64;
+
+/// Bytes a fresh mapping holds on the device side until the cpu flushes.
+pub const POISON: u8 = 0xa5;
+
+struct Shadow {
+    addr: usize,
+    bus_addr: u64,
+    /// what the device sees, `data.len()` is the mapping size
+    data: Vec<u8>,
+    /// cpu bytes as of the last sync, lines differing from it are dirty
+    cached: Vec<u8>,
+}
+
+/// A non-coherent cache model for running driver code on the host.
+///
+/// Every streaming mapping gets a separate device side copy: `flush` copies
+/// dirty cpu lines to the device, `invalidate` copies device to cpu, and the
+/// test acts as the device through [`CacheSimOsal::device_read`] and [`CacheSimOsal::device_write`].
+/// A new mapping reads as [`POISON`] on the device side, so a missing
+/// `confirm_write` or `prepare_read` shows up as stale data instead of working
+/// by accident like it does on a coherent host.
+///
+/// Bus addresses are translated the same way as [`RecordingOsal`].
+pub struct CacheSimOsal {
+    inner: Mutex<SimInner>,
+}
+
+struct SimInner {
+    next_iova: u64,
+    shadows: Vec<Shadow>,
+}
+
+impl Default for CacheSimOsal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CacheSimOsal {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(SimInner {
+                next_iova: 0x1000_0000,
+                shadows: Vec::new(),
+            }),
+        }
+    }
+
+    /// Act as the device reading `buf.len()` bytes at `bus_addr`, sees only flushed data.
+    ///
+    /// # Panics
+    /// If the range is not inside one live mapping.
+    pub fn device_read(&self, bus_addr: u64, buf: &mut [u8]) {
+        let mut inner = self.inner.lock();
+        let (shadow, offset) = inner.shadow(bus_addr, buf.len());
+        buf.copy_from_slice(&shadow.data[offset..offset + buf.len()]);
+    }
+
+    /// Act as the device writing `data` at `bus_addr`, the cpu sees it after invalidating.
+    ///
+    /// # Panics
+    /// If the range is not inside one live mapping.
+    pub fn device_write(&self, bus_addr: u64, data: &[u8]) {
+        let mut inner = self.inner.lock();
+        let (shadow, offset) = inner.shadow(bus_addr, data.len());
+        shadow.data[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Sync the mapped lines in `addr..addr + size`, `to_device` picks the direction.
+    fn sync(&self, addr: NonNull<u8>, size: usize, to_device: bool) {
+        let start = addr.as_ptr() as usize;
+        let end = start + size;
+        let mut inner = self.inner.lock();
+        for shadow in inner.shadows.iter_mut() {
+            let map_end = shadow.addr + shadow.data.len();
+            let mut line = start.max(shadow.addr) & !(SIM_LINE - 1);
+            while line < end.min(map_end) {
+                let s = line.max(shadow.addr).max(start);
+                let e = (line + SIM_LINE).min(map_end).min(end);
+                line += SIM_LINE;
+                if s >= e {
+                    continue;
+                }
+                let r = s - shadow.addr..e - shadow.addr;
+                let cpu = unsafe { core::slice::from_raw_parts_mut(s as *mut u8, e - s) };
+                if to_device {
+                    // only dirty lines are written back
+                    if cpu != &shadow.cached[r.clone()] {
+                        shadow.data[r.clone()].copy_from_slice(cpu);
+                        shadow.cached[r].copy_from_slice(cpu);
+                    }
+                } else {
+                    cpu.copy_from_slice(&shadow.data[r.clone()]);
+                    shadow.cached[r].copy_from_slice(cpu);
+                }
+            }
+        }
+    }
+}
+
+impl SimInner {
+    fn shadow(&mut self, bus_addr: u64, size: usize) -> (&mut Shadow, usize) {
+        let shadow = self
+            .shadows
+            .iter_mut()
+            .find(|m| {
+                bus_addr >= m.bus_addr && bus_addr + size as u64 <= m.bus_addr + m.data.len() as u64
+            })
+            .unwrap_or_else(|| panic!("{bus_addr:#x} size {size:#x} is not a mapped bus address"));
+        let offset = (bus_addr - shadow.bus_addr) as usize;
+        (shadow, offset)
+    }
+}
+
+impl Osal for CacheSimOsal {
+    fn map(&self, addr: NonNull<u8>, size: usize, _direction: Direction) -> u64 {
+        let addr = addr.as_ptr() as usize;
+        let mut inner = self.inner.lock();
+        let bus_addr = alloc_iova(&mut inner.next_iova, addr, size);
+        inner.shadows.push(Shadow {
+            addr,
+            bus_addr,
+            data: vec![POISON; size],
+            cached: vec![POISON; size],
+        });
+        bus_addr
+    }
+
+    fn unmap(&self, addr: NonNull<u8>, size: usize) {
+        let addr = addr.as_ptr() as usize;
+        let mut inner = self.inner.lock();
+        let i = inner
+            .shadows
+            .iter()
+            .position(|m| m.addr == addr && m.data.len() == size)
+            .unwrap_or_else(|| panic!("unmap of {addr:#x} size {size:#x} which is not mapped"));
+        inner.shadows.swap_remove(i);
+    }
+
+    fn flush(&self, addr: NonNull<u8>, size: usize) {
+        self.sync(addr, size, true);
+    }
+
+    fn invalidate(&self, addr: NonNull<u8>, size: usize) {
+        self.sync(addr, size, false);
+    }
+
+    fn flush_invalidate(&self, addr: NonNull<u8>, size: usize) {
+        self.sync(addr, size, true);
+        self.sync(addr, size, false);
+    }
+
+    fn cache_line_size(&self) -> usize {
+        SIM_LINE
+    }
+
+    fn is_coherent(&self) -> bool {
+        false
+    }
+}
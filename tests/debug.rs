@@ -0,0 +1,145 @@
+use std::{ptr::NonNull, sync::Mutex};
+
+use dma_api::{debug::*, *};
+
+static REPORTS: Mutex<Vec<DebugReport>> = Mutex::new(Vec::new());
+static BOUNCE: BounceRegion = BounceRegion::new();
+
+struct Collect;
+
+impl DebugSink for Collect {
+    fn report(&self, report: &DebugReport) {
+        REPORTS.lock().unwrap().push(*report);
+    }
+}
+
+struct Identity;
+
+impl Osal for Identity {
+    fn map(&self, addr: NonNull<u8>, _size: usize, _direction: Direction) -> u64 {
+        addr.as_ptr() as usize as _
+    }
+
+    fn unmap(&self, _addr: NonNull<u8>, _size: usize) {}
+
+    fn flush(&self, _addr: NonNull<u8>, _size: usize) {}
+
+    fn invalidate(&self, _addr: NonNull<u8>, _size: usize) {}
+
+    fn flush_invalidate(&self, _addr: NonNull<u8>, _size: usize) {}
+
+    fn bounce_region(&self) -> Option<&BounceRegion> {
+        Some(&BOUNCE)
+    }
+}
+
+fn take() -> Vec<DebugReport> {
+    std::mem::take(&mut *REPORTS.lock().unwrap())
+}
+
+// one test, the registry is global to the process
+#[test]
+fn test_dma_debug() {
+    set_sink(&Collect);
+    let dev = DmaDevice::new(&Identity, u64::MAX);
+
+    let mut rx: DVec<u8> = DVec::zeros_in(&dev, 0x40, 0x40, Direction::FromDevice).unwrap();
+    assert_eq!(take(), []);
+    rx.set(1, 1);
+    assert_eq!(
+        take(),
+        [DebugReport::CpuWriteFromDevice {
+            addr: rx.as_ptr() as usize + 1,
+            size: 1
+        }]
+    );
+
+    // still mapped at the checkpoint, handed to the device and back to the cpu by the `get`
+    rx.sync_for_device(..);
+    let _ = rx.get(0);
+    assert_eq!(checkpoint(), 1);
+    assert_eq!(
+        take(),
+        [DebugReport::Leaked {
+            addr: rx.as_ptr() as usize,
+            size: 0x40,
+            direction: Direction::FromDevice,
+            owner: Owner::Cpu,
+        }]
+    );
+    drop(rx);
+    assert_eq!(checkpoint(), 0);
+
+    let data = [0u32; 4];
+    let a = DSlice::from_in(&dev, &data, Direction::ToDevice).unwrap();
+    let b = DSlice::from_in(&dev, &data[2..], Direction::ToDevice).unwrap();
+    assert_eq!(
+        take(),
+        [DebugReport::Overlap {
+            addr: data[2..].as_ptr() as usize,
+            size: 8,
+            other: data.as_ptr() as usize,
+            other_size: 16,
+        }]
+    );
+    drop((a, b));
+
+    let ptr = NonNull::new(data.as_ptr() as *mut u8).unwrap();
+    dev.sync_for_device(Direction::ToDevice, ptr, 16);
+    dev.flush(ptr, 16);
+    assert_eq!(
+        take(),
+        [DebugReport::SyncOutsideMapping {
+            addr: ptr.as_ptr() as usize,
+            size: 16
+        }; 2]
+    );
+
+    // element writes keep the buffer with the cpu, a handoff gives it away
+    let mut buf: DVec<u8> = DVec::zeros_in(&dev, 0x40, 0x40, Direction::Bidirectional).unwrap();
+    buf.set(0, 1);
+    assert_eq!(take(), []);
+    buf.sync_for_device(..);
+    buf.set(0, 2);
+    let addr = buf.as_ptr() as usize;
+    drop(buf);
+    assert_eq!(
+        take(),
+        [
+            DebugReport::CpuWriteDeviceOwned { addr, size: 1 },
+            DebugReport::UnmapDeviceOwned { addr, size: 0x40 },
+        ]
+    );
+    assert_eq!(checkpoint(), 0);
+
+    // a bounced mapping is checked against the original buffer
+    const BUS: u64 = 0x10_0000;
+    let region = Box::leak(vec![0u8; 0x10000].into_boxed_slice());
+    unsafe {
+        BOUNCE.init(
+            NonNull::new(region.as_mut_ptr()).unwrap(),
+            BUS,
+            region.len(),
+        )
+    };
+    let low = DmaDevice::new(&Identity, 0xFFF_FFFF);
+    let bounced = DVec::from_vec_in(&low, vec![0u32; 4], Direction::FromDevice).unwrap();
+    assert!(bounced.bus_addr() >= BUS && bounced.bus_addr() < BUS + 0x10000);
+    let addr = bounced.as_ptr() as usize;
+    bounced.sync_for_device(..);
+    assert_eq!(take(), []);
+    assert_eq!(checkpoint(), 1);
+    assert_eq!(
+        take(),
+        [DebugReport::Leaked {
+            addr,
+            size: 16,
+            direction: Direction::FromDevice,
+            owner: Owner::Device,
+        }]
+    );
+    let _ = bounced.get(0);
+    drop(bounced);
+    assert_eq!(checkpoint(), 0);
+    assert_eq!(take(), []);
+}
@@ -1,3 +1,5 @@
+use std::ptr::NonNull;
+
 use dma_api::*;
 
 #[test]
@@ -131,8 +133,856 @@ fn test_from_vec() {
     assert_eq!(v, vec![1, 2, 3]);
 }
 
+#[test]
+fn test_from_vec_capacity() {
+    init(&Impled);
+    let mut value = Vec::with_capacity(8);
+    value.extend_from_slice(&[1u32, 2, 3]);
+    let mut dma = DVec::from_vec(u64::MAX, value, Direction::Bidirectional).unwrap();
+    assert_eq!(dma.len(), 3);
+    assert_eq!(dma.capacity(), 8);
+    dma.push(4).unwrap();
+    assert_eq!(dma.to_vec(), vec![1, 2, 3, 4]);
+
+    // cache line aligned `Osal::alloc` memory is copied out, not handed to `Vec`
+    let mut dma: DVec<u32> = DVec::zeros(u64::MAX, 3, 0x40, Direction::Bidirectional).unwrap();
+    dma.set(2, 7);
+    assert_eq!(dma.to_vec(), vec![0, 0, 7]);
+}
+
+#[test]
+fn test_sg_list() {
+    init(&Impled);
+    let src = [1u8; 0x40];
+    let dma: DVec<u8> = DVec::zeros(u64::MAX, 0x40, 0x1000, Direction::ToDevice).unwrap();
+
+    let mut sg = DSgList::new(Direction::ToDevice);
+    sg.push_slice(&src[..0x20]).unwrap();
+    sg.push_slice(&src[0x20..]).unwrap();
+    sg.push_dvec(&dma);
+
+    assert_eq!(sg.len(), 3);
+    assert_eq!(sg.total_size(), 0x80);
+
+    let segs: Vec<_> = sg.segments().collect();
+    assert_eq!(segs[0], (src.as_ptr() as u64, 0x40));
+    assert_eq!(segs[1], (dma.bus_addr(), 0x40));
+
+    sg.confirm_write_all();
+
+    let mut buf = [0u8; 0x40];
+    let mut rx = DSgList::new(Direction::FromDevice);
+    assert!(matches!(
+        rx.push_slice(&src),
+        Err(DError::ReadOnly {
+            direction: Direction::FromDevice
+        })
+    ));
+    rx.push_slice_mut(&mut buf).unwrap();
+    assert_eq!(rx.len(), 1);
+}
+
+#[test]
+fn test_bounce() {
+    init(&Impled);
+    const BUS: u64 = 0x10_0000;
+    const MASK: u64 = 0xFFF_FFFF;
+
+    let region = Box::leak(vec![0u8; 0x10000].into_boxed_slice());
+    let region_ptr = region.as_mut_ptr();
+    unsafe { BOUNCE.init(NonNull::new(region_ptr).unwrap(), BUS, region.len()) };
+
+    let mut dma = DVec::from_vec(MASK, vec![1u32, 2, 3], Direction::Bidirectional).unwrap();
+    let offset = (dma.bus_addr() - BUS) as usize;
+    assert!(offset < 0x10000);
+    let device = unsafe { region_ptr.add(offset) as *mut u32 };
+
+    dma.set(1, 5);
+    assert_eq!(unsafe { device.add(1).read() }, 5);
+
+    unsafe { device.add(2).write(7) };
+    assert_eq!(dma.get(2), Some(7));
+
+    let src = [9u8; 0x20];
+    let slice = DSlice::from_with_mask(src.as_ref(), MASK, Direction::ToDevice).unwrap();
+    let offset = (slice.bus_addr() - BUS) as usize;
+    assert_eq!(unsafe { region_ptr.add(offset).read() }, 9);
+
+    // bouncing back would write into `src`, which is only borrowed shared
+    assert!(matches!(
+        DSlice::from_with_mask(src.as_ref(), MASK, Direction::FromDevice),
+        Err(DError::DmaMaskNotMatch { .. })
+    ));
+    let mut rx = [0u8; 0x20];
+    let slice_mut = DSliceMut::from_with_mask(rx.as_mut(), MASK, Direction::FromDevice).unwrap();
+    let offset = (slice_mut.bus_addr() - BUS) as usize;
+    unsafe { region_ptr.add(offset).write(3) };
+    drop(slice_mut);
+    assert_eq!(rx[0], 3);
+
+    let dev = DmaDevice::global(MASK);
+    let mut sg = DSgList::new_in(&dev, Direction::FromDevice);
+    sg.push_slice_mut(rx.as_mut()).unwrap();
+    let (bus_addr, len) = sg.segments().next().unwrap();
+    assert_eq!(len, 0x20);
+    assert!(bus_addr & !MASK == 0);
+    unsafe { region_ptr.add((bus_addr - BUS) as usize).write(4) };
+    drop(sg);
+    assert_eq!(rx[0], 4);
+
+    let stats = BOUNCE.stats().unwrap();
+    assert!(stats.mapped >= 2);
+    assert!(stats.used_slots >= 2);
+
+    // the region belongs to `Impled`, bus addresses behind `Offset` differ
+    let offset = DmaDevice::new(&Offset, MASK);
+    assert!(matches!(
+        DVec::from_vec_in(&offset, vec![1u32, 2, 3], Direction::Bidirectional),
+        Err(DError::DmaMaskNotMatch { .. })
+    ));
+    assert_eq!(BOUNCE.stats().unwrap().mapped, stats.mapped);
+}
+
+#[test]
+fn test_coherent() {
+    init(&Impled);
+    assert_eq!(is_coherent(), cfg!(target_arch = "x86_64"));
+
+    let mut ring: DCoherentVec<u32> = DCoherentVec::zeros(u64::MAX, 0x10, 0x1000).unwrap();
+    assert_eq!(ring.len(), 0x10);
+    assert_eq!(ring.bus_addr() % 0x1000, 0);
+
+    ring.set(3, 7);
+    assert_eq!(ring.get(3), Some(7));
+    assert_eq!(ring.as_ref()[3], 7);
+
+    let mut desc: DCoherentBox<Foo> = DCoherentBox::zero(u64::MAX).unwrap();
+    desc.modify(|f| f.foo = 2);
+    assert_eq!(desc.read(), Foo { foo: 2, bar: 0 });
+
+    // never handed to `alloc_coherent`, a zero-size layout is UB for `alloc_zeroed`
+    let empty: DCoherentVec<u32> = DCoherentVec::zeros(u64::MAX, 0, 0x40).unwrap();
+    assert_eq!(empty.len(), 0);
+    assert_eq!(empty.get(0), None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Desc {
+    addr: u64,
+    own: u32,
+}
+
+#[test]
+fn test_ring() {
+    init(&Impled);
+    let mut ring: DRing<Desc> = DRing::new(u64::MAX, 4, 0x1000, |d: &Desc| d.own == 0).unwrap();
+
+    let rung = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let r = rung.clone();
+    ring.set_doorbell(move |head| r.store(head, std::sync::atomic::Ordering::SeqCst));
+
+    for i in 0..4 {
+        assert_eq!(ring.push(Desc { addr: i, own: 1 }), Ok(i as usize));
+    }
+    assert!(ring.is_full());
+    assert!(ring.push(Desc { addr: 4, own: 1 }).is_err());
+    assert_eq!(rung.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+    assert_eq!(ring.pop_completed(), None);
+
+    // device completes the first descriptor
+    unsafe { (*(ring.bus_addr() as usize as *mut Desc)).own = 0 };
+
+    assert_eq!(ring.peek(), Some(Desc { addr: 0, own: 0 }));
+    assert_eq!(ring.pop_completed(), Some((0, Desc { addr: 0, own: 0 })));
+    assert_eq!(ring.len(), 3);
+    assert_eq!(ring.push(Desc { addr: 4, own: 1 }), Ok(0));
+}
+
+struct Offset;
+
+const OFFSET: u64 = 0x1_0000_0000;
+
+impl Osal for Offset {
+    fn map(&self, addr: NonNull<u8>, _size: usize, _direction: Direction) -> u64 {
+        addr.as_ptr() as u64 + OFFSET
+    }
+
+    fn unmap(&self, _addr: NonNull<u8>, _size: usize) {}
+
+    fn is_coherent(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_device() {
+    init(&Impled);
+    let dev = DmaDevice::new(&Offset, u64::MAX);
+    assert!(dev.is_coherent());
+
+    let dma: DVec<u32> = DVec::zeros_in(&dev, 0x10, 0x1000, Direction::ToDevice).unwrap();
+    assert_eq!(dma.bus_addr(), dma.as_ptr() as u64 + OFFSET);
+
+    let src = [1u32; 4];
+    let slice = DSlice::from_in(&dev, &src, Direction::ToDevice).unwrap();
+    assert_eq!(slice.bus_addr(), src.as_ptr() as u64 + OFFSET);
+
+    let global = DVec::<u32>::zeros(u64::MAX, 0x10, 0x1000, Direction::ToDevice).unwrap();
+    assert_eq!(global.bus_addr(), global.as_ptr() as u64);
+}
+
+struct Exhausted;
+
+impl Osal for Exhausted {
+    fn map(&self, _addr: NonNull<u8>, _size: usize, _direction: Direction) -> u64 {
+        unreachable!()
+    }
+
+    fn try_map(
+        &self,
+        _addr: NonNull<u8>,
+        _size: usize,
+        _direction: Direction,
+        _dma_mask: u64,
+    ) -> Result<u64, DError> {
+        Err(DError::IommuExhausted)
+    }
+
+    fn unmap(&self, _addr: NonNull<u8>, _size: usize) {
+        panic!("nothing was mapped");
+    }
+}
+
+#[test]
+fn test_map_failed() {
+    init(&Impled);
+    let dev = DmaDevice::new(&Exhausted, u64::MAX);
+
+    let r = DVec::<u32>::zeros_in(&dev, 0x10, 0x1000, Direction::ToDevice);
+    assert!(matches!(r, Err(DError::IommuExhausted)));
+
+    let r = DVec::from_vec_in(&dev, vec![1u32, 2], Direction::ToDevice);
+    assert!(matches!(r, Err(DError::IommuExhausted)));
+
+    let src = [1u32; 4];
+    let r = DSlice::from_in(&dev, &src, Direction::ToDevice);
+    assert!(matches!(r, Err(DError::IommuExhausted)));
+
+    assert!(DSlice::try_from(&src, Direction::ToDevice).is_ok());
+}
+
+#[test]
+fn test_transfer() {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+    };
+
+    struct Flag(std::sync::atomic::AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    init(&Impled);
+    let mut dma: DVec<u32> = DVec::zeros(u64::MAX, 0x10, 0x1000, Direction::Bidirectional).unwrap();
+    dma.set(0, 1);
+    let bus_addr = dma.bus_addr();
+
+    let (transfer, completer) = DTransfer::start(dma);
+    assert_eq!(transfer.bus_addr(), bus_addr);
+
+    let flag = Arc::new(Flag(Default::default()));
+    let waker = flag.clone().into();
+    let mut cx = Context::from_waker(&waker);
+    let mut transfer = pin!(transfer);
+
+    assert!(transfer.as_mut().poll(&mut cx).is_pending());
+
+    // device writes and raises an IRQ
+    unsafe { (bus_addr as usize as *mut u32).add(1).write(2) };
+    completer.complete();
+    assert!(flag.0.load(std::sync::atomic::Ordering::SeqCst));
+
+    let Poll::Ready(dma) = transfer.as_mut().poll(&mut cx) else {
+        panic!("transfer not ready");
+    };
+    assert_eq!(dma.get(1), Some(2));
+}
+
+#[test]
+fn test_transfer_complete_while_registering() {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            OnceLock,
+        },
+        task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    };
+
+    // an IRQ arriving while `poll` registers its waker, the clone runs `complete`
+    static COMPLETER: OnceLock<DCompleter> = OnceLock::new();
+    static WOKEN: AtomicBool = AtomicBool::new(false);
+
+    fn clone(_: *const ()) -> RawWaker {
+        if let Some(completer) = COMPLETER.get() {
+            completer.complete();
+        }
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WOKEN.store(true, Ordering::SeqCst);
+    }
+    fn drop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+    init(&Impled);
+    let dma: DVec<u32> = DVec::zeros(u64::MAX, 0x10, 0x1000, Direction::FromDevice).unwrap();
+    let (transfer, completer) = DTransfer::start(dma);
+    let mut transfer = pin!(transfer);
+
+    let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    let mut cx = Context::from_waker(&waker);
+    let _ = COMPLETER.set(completer);
+
+    assert!(matches!(transfer.as_mut().poll(&mut cx), Poll::Ready(_)));
+    assert!(WOKEN.load(Ordering::SeqCst));
+}
+
+struct ArchDefault;
+
+impl Osal for ArchDefault {
+    fn map(&self, addr: NonNull<u8>, _size: usize, _direction: Direction) -> u64 {
+        addr.as_ptr() as usize as _
+    }
+
+    fn unmap(&self, _addr: NonNull<u8>, _size: usize) {}
+}
+
+#[test]
+fn test_arch_cache_ops() {
+    let mut buff = vec![0u8; 0x1000];
+    let addr = NonNull::new(buff[3..].as_mut_ptr()).unwrap();
+
+    buff[3] = 1;
+    ArchDefault.flush(addr, 0x100);
+    ArchDefault.invalidate(addr, 0x100);
+    assert_eq!(buff[3], 1);
+
+    buff[3] = 2;
+    ArchDefault.flush_invalidate(addr, 0x100);
+    assert_eq!(buff[3], 2);
+}
+
+#[test]
+#[should_panic]
+fn test_sync_range_out_of_bounds() {
+    init(&Impled);
+    let dma: DVec<u32> = DVec::zeros(u64::MAX, 4, 0x40, Direction::ToDevice).unwrap();
+    dma.sync_for_device(2..5);
+}
+
+#[test]
+fn test_grow() {
+    init(&Impled);
+    let mut dma: DVec<u32> = DVec::zeros(u64::MAX, 0, 0x40, Direction::ToDevice).unwrap();
+    assert!(dma.is_empty());
+    assert_eq!(dma.capacity(), 0);
+
+    let moved = dma.push(1).unwrap();
+    assert_eq!(moved, Some(dma.bus_addr()));
+    assert_eq!(dma.len(), 1);
+
+    let cap = dma.capacity();
+    assert!(cap >= 0x40 / 4);
+    assert_eq!(dma.push(2).unwrap(), None);
+
+    let more: Vec<u32> = (3..=cap as u32 + 1).collect();
+    assert!(dma.extend_from_slice(&more).unwrap().is_some());
+    assert_eq!(dma.len(), cap + 1);
+    assert_eq!(dma.get(cap), Some(cap as u32 + 1));
+    assert_eq!(dma.get(0), Some(1));
+
+    dma.truncate(2);
+    assert_eq!(dma.len(), 2);
+    assert_eq!(dma.get(2), None);
+
+    dma.clear();
+    assert!(dma.is_empty());
+}
+
+#[test]
+fn test_pool_limits() {
+    init(&Impled);
+    let config = DVecConfig {
+        dma_mask: u64::MAX,
+        align: 0x40,
+        size: 0x100,
+        direction: Direction::FromDevice,
+    };
+    let pool = DVecPool::new_pool(config, 1);
+    pool.set_limits(DVecPoolLimits {
+        max_outstanding: 2,
+        policy: ExhaustPolicy::Fail,
+        low_watermark: 1,
+        high_watermark: 1,
+    });
+
+    let a = pool.alloc().unwrap();
+    // the low watermark topped the idle buffers back up
+    assert_eq!(pool.idle(), 1);
+    let b = pool.alloc().unwrap();
+    assert_eq!(pool.outstanding(), 2);
+    // refill stops at max_outstanding
+    assert_eq!(pool.idle(), 0);
+    assert!(matches!(pool.alloc(), Err(DError::PoolExhausted)));
+
+    pool.set_limits(DVecPoolLimits {
+        max_outstanding: 2,
+        policy: ExhaustPolicy::Spin(Some(100)),
+        ..Default::default()
+    });
+    assert!(matches!(pool.alloc(), Err(DError::PoolExhausted)));
+
+    pool.set_limits(DVecPoolLimits {
+        max_outstanding: 2,
+        policy: ExhaustPolicy::Grow { hard_cap: 3 },
+        high_watermark: 1,
+        ..Default::default()
+    });
+    let c = pool.alloc().unwrap();
+    assert!(matches!(pool.alloc(), Err(DError::PoolExhausted)));
+
+    drop((a, b, c));
+    assert_eq!(pool.outstanding(), 0);
+    assert_eq!(pool.idle(), 3);
+    assert_eq!(pool.shrink(), 2);
+    assert_eq!(pool.idle(), 1);
+}
+
+#[test]
+fn test_pool_grown_buffer() {
+    init(&Impled);
+    let config = DVecConfig {
+        dma_mask: u64::MAX,
+        align: 0x40,
+        size: 0x100,
+        direction: Direction::Bidirectional,
+    };
+    let pool = DVecPool::new_pool(config, 1);
+
+    let mut buff = pool.alloc().unwrap();
+    buff.push(1).unwrap();
+    assert!(buff.capacity() > 0x100);
+    drop(buff);
+    // the grown buffer is freed, not handed out again
+    assert_eq!(pool.outstanding(), 0);
+    assert_eq!(pool.idle(), 0);
+
+    let mut buff = pool.alloc().unwrap();
+    assert_eq!(buff.capacity(), 0x100);
+    buff.truncate(4);
+    drop(buff);
+    assert_eq!(pool.idle(), 1);
+    assert_eq!(pool.alloc().unwrap().len(), 0x100);
+}
+
+#[test]
+fn test_pool_async() {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    init(&Impled);
+    let config = DVecConfig {
+        dma_mask: u64::MAX,
+        align: 0x40,
+        size: 0x100,
+        direction: Direction::FromDevice,
+    };
+    let pool = DVecPool::new_pool(config, 1);
+    pool.set_limits(DVecPoolLimits {
+        max_outstanding: 1,
+        ..Default::default()
+    });
+    let buff = pool.alloc().unwrap();
+
+    let flags = [0, 1].map(|_| Arc::new(Flag(AtomicBool::new(false))));
+    let wakers = flags.clone().map(Waker::from);
+    let mut first = pin!(pool.alloc_async());
+    let mut second = pin!(pool.alloc_async());
+    assert!(first
+        .as_mut()
+        .poll(&mut Context::from_waker(&wakers[0]))
+        .is_pending());
+    assert!(second
+        .as_mut()
+        .poll(&mut Context::from_waker(&wakers[1]))
+        .is_pending());
+
+    // returning a buffer wakes only the oldest waiter
+    drop(buff);
+    assert!(flags[0].0.load(Ordering::SeqCst));
+    assert!(!flags[1].0.load(Ordering::SeqCst));
+
+    let Poll::Ready(Ok(buff)) = first.as_mut().poll(&mut Context::from_waker(&wakers[0])) else {
+        panic!("first waiter not ready");
+    };
+    assert!(second
+        .as_mut()
+        .poll(&mut Context::from_waker(&wakers[1]))
+        .is_pending());
+
+    drop(buff);
+    assert!(flags[1].0.load(Ordering::SeqCst));
+    assert!(matches!(
+        second.as_mut().poll(&mut Context::from_waker(&wakers[1])),
+        Poll::Ready(Ok(_))
+    ));
+}
+
+#[test]
+fn test_pool_async_order() {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Context, Wake, Waker},
+    };
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    init(&Impled);
+    let config = DVecConfig {
+        dma_mask: u64::MAX,
+        align: 0x40,
+        size: 0x100,
+        direction: Direction::FromDevice,
+    };
+    let pool = DVecPool::new_pool(config, 1);
+    pool.set_limits(DVecPoolLimits {
+        max_outstanding: 1,
+        ..Default::default()
+    });
+    let buff = pool.alloc().unwrap();
+
+    let flags = [0, 1, 2].map(|_| Arc::new(Flag(AtomicBool::new(false))));
+    let wakers = flags.clone().map(Waker::from);
+    let mut first = pin!(pool.alloc_async());
+    let mut second = pin!(pool.alloc_async());
+    assert!(first
+        .as_mut()
+        .poll(&mut Context::from_waker(&wakers[0]))
+        .is_pending());
+    assert!(second
+        .as_mut()
+        .poll(&mut Context::from_waker(&wakers[1]))
+        .is_pending());
+
+    // the woken waiter loses the buffer to a plain alloc
+    drop(buff);
+    assert!(flags[0].0.swap(false, Ordering::SeqCst));
+    let stolen = pool.alloc().unwrap();
+    assert!(first
+        .as_mut()
+        .poll(&mut Context::from_waker(&wakers[0]))
+        .is_pending());
+
+    // a new future queues behind both instead of taking the returned buffer
+    drop(stolen);
+    let mut third = pin!(pool.alloc_async());
+    assert!(third
+        .as_mut()
+        .poll(&mut Context::from_waker(&wakers[2]))
+        .is_pending());
+
+    // the first waiter kept its place
+    assert!(flags[0].0.load(Ordering::SeqCst));
+    assert!(!flags[1].0.load(Ordering::SeqCst));
+    assert!(first
+        .as_mut()
+        .poll(&mut Context::from_waker(&wakers[0]))
+        .is_ready());
+    assert!(!flags[2].0.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_pool_magazines() {
+    init(&Impled);
+    let config = DVecConfig {
+        dma_mask: u64::MAX,
+        align: 0x40,
+        size: 0x100,
+        direction: Direction::FromDevice,
+    };
+    let pool = DVecPool::new_pool_cached(config, 4, DVecMagazines { count: 2, size: 2 });
+
+    let a = pool.alloc_on(0).unwrap();
+    let bus_addr = a.bus_addr();
+    drop(a);
+    assert_eq!(pool.idle(), 4);
+
+    // queue 0 gets its own buffer back from its magazine, queue 1 refills from the depot
+    assert_eq!(pool.alloc_on(0).unwrap().bus_addr(), bus_addr);
+    assert_ne!(pool.alloc_on(1).unwrap().bus_addr(), bus_addr);
+
+    // drains the depot and allocates one, queue 1 keeps its cached buffer
+    let buffs: Vec<_> = (0..4).map(|_| pool.alloc_on(0).unwrap()).collect();
+    assert_eq!(pool.outstanding(), 4);
+    assert_eq!(pool.idle(), 1);
+    // overflowing a magazine moves half of it back to the depot
+    drop(buffs);
+    assert_eq!(pool.outstanding(), 0);
+    assert_eq!(pool.idle(), 5);
+
+    pool.set_limits(DVecPoolLimits {
+        high_watermark: 1,
+        ..Default::default()
+    });
+    assert_eq!(pool.shrink(), 4);
+    assert_eq!(pool.idle(), 1);
+}
+
+#[test]
+fn test_size_class_pool() {
+    init(&Impled);
+    let classes = [
+        DSizeClass {
+            size: 0x2400,
+            align: 0x40,
+            cap: 0,
+        },
+        DSizeClass {
+            size: 0x100,
+            align: 0x40,
+            cap: 2,
+        },
+        DSizeClass {
+            size: 0x800,
+            align: 0x40,
+            cap: 1,
+        },
+    ];
+    let pool = DSizeClassPool::new(u64::MAX, Direction::FromDevice, &classes);
+
+    let small = pool.alloc(0x40).unwrap();
+    assert_eq!(small.len(), 0x40);
+    assert_eq!(small.capacity(), 0x100);
+    let mtu = pool.alloc(1500).unwrap();
+    assert_eq!(mtu.capacity(), 0x800);
+    let jumbo = pool.alloc(9000).unwrap();
+    assert_eq!(jumbo.capacity(), 0x2400);
+    assert!(matches!(
+        pool.alloc(0x3000),
+        Err(DError::NoSizeClass { len: 0x3000 })
+    ));
+
+    pool.class(0x800).unwrap().set_limits(DVecPoolLimits {
+        max_outstanding: 1,
+        ..Default::default()
+    });
+    assert!(matches!(pool.alloc(0x800), Err(DError::PoolExhausted)));
+
+    let stats = pool.stats();
+    assert_eq!(
+        stats.iter().map(|s| s.size).collect::<Vec<_>>(),
+        [0x100, 0x800, 0x2400]
+    );
+    assert_eq!(stats[0].allocs, 1);
+    assert_eq!(stats[0].idle, 1);
+    assert_eq!(stats[1].failures, 1);
+
+    drop((small, mtu, jumbo));
+    let stats = pool.stats();
+    assert!(stats.iter().all(|s| s.outstanding == 0));
+    assert_eq!(stats[0].idle, 2);
+    assert_eq!(stats[2].idle, 1);
+}
+
+#[test]
+fn test_iova() {
+    let iova = IovaAllocator::new(0x1000_0000..0x1010_0000, 0x1000);
+    let total = iova.free_size();
+
+    // below the mask, top down
+    let a = iova.alloc(0x100, 0x1007_ffff).unwrap();
+    assert_eq!(a, 0x1007_f000);
+    let b = iova.alloc(0x1000, u64::MAX).unwrap();
+    assert_eq!(b, 0x100f_f000);
+
+    // small sizes round to a power of two and are size aligned
+    let c = iova.alloc(0x3000, u64::MAX).unwrap();
+    assert!(c.is_multiple_of(0x4000));
+    let d = iova.alloc_aligned(0x30000, u64::MAX).unwrap();
+    assert!(d.is_multiple_of(0x40000));
+
+    assert!(matches!(
+        iova.alloc(0x1000, 0xfff_ffff),
+        Err(DError::IommuExhausted)
+    ));
+
+    // freed ranges come back from the cache
+    iova.free(c, 0x3000);
+    assert_eq!(iova.alloc(0x4000, u64::MAX).unwrap(), c);
+
+    for (addr, size) in [(a, 0x100), (b, 0x1000), (c, 0x4000), (d, 0x30000)] {
+        iova.free(addr, size);
+    }
+    assert_eq!(iova.free_size(), total);
+    // everything merges back into one range once the cache is flushed
+    assert_eq!(iova.alloc(0x10_0000, u64::MAX).unwrap(), 0x1000_0000);
+    assert!(iova.alloc(0x1000, u64::MAX).is_err());
+}
+
+#[test]
+fn test_iova_device_mask() {
+    use std::sync::LazyLock;
+
+    static IOVA: LazyLock<IovaAllocator> =
+        LazyLock::new(|| IovaAllocator::new(0x1000_0000..0x1010_0000, 0x1000));
+
+    struct Iommu;
+
+    impl Osal for Iommu {
+        fn map(&self, _addr: NonNull<u8>, _size: usize, _direction: Direction) -> u64 {
+            unreachable!()
+        }
+
+        fn try_map(
+            &self,
+            _addr: NonNull<u8>,
+            size: usize,
+            _direction: Direction,
+            dma_mask: u64,
+        ) -> Result<u64, DError> {
+            IOVA.alloc(size, dma_mask)
+        }
+
+        fn unmap(&self, _addr: NonNull<u8>, _size: usize) {}
+    }
+
+    let dev = DmaDevice::new(&Iommu, 0x1007_ffff);
+    let dma: DVec<u32> = DVec::zeros_in(&dev, 0x10, 0x40, Direction::ToDevice).unwrap();
+    assert_eq!(dma.bus_addr(), 0x1007_f000);
+}
+
+#[test]
+#[should_panic(expected = "double free")]
+fn test_iova_double_free_cached() {
+    let iova = IovaAllocator::new(0x1000_0000..0x1010_0000, 0x1000);
+    let a = iova.alloc(0x1000, u64::MAX).unwrap();
+    iova.free(a, 0x1000);
+    iova.free(a, 0x1000);
+}
+
+#[test]
+fn test_io_pgtable() {
+    init(&Impled);
+    let dev = DmaDevice::global(u64::MAX);
+
+    // Impled maps 1:1, so table bus addresses can be followed on the host
+    fn leaf(root: u64, iova: u64) -> u64 {
+        let mut table = root;
+        for level in 0..4 {
+            let i = (iova >> (12 + 9 * (3 - level))) & 0x1ff;
+            let pte = unsafe { (table as usize as *const u64).add(i as usize).read() };
+            if level == 3 || pte & 3 == 1 {
+                return pte;
+            }
+            table = pte & 0xffff_ffff_f000;
+        }
+        unreachable!()
+    }
+
+    let mut pt = IoPageTable::new(&dev, LpaeStage::Stage1).unwrap();
+    pt.map(
+        0x1000_0000,
+        0x8000_0000,
+        0x2000,
+        IoProt::READ | IoProt::WRITE,
+    )
+    .unwrap();
+    assert_eq!(pt.iova_to_phys(0x1000_1123), Some(0x8000_1123));
+    assert_eq!(pt.iova_to_phys(0x1000_2000), None);
+    let pte = leaf(pt.root_bus_addr(), 0x1000_0000);
+    assert_eq!(pte & 3, 3);
+    assert_eq!(pte & 0xffff_ffff_f000, 0x8000_0000);
+    assert_eq!(pte & (1 << 7), 0, "writable");
+
+    // 2M aligned on both sides becomes a block
+    pt.map(
+        0x4020_0000,
+        0x1_0020_0000,
+        0x20_1000,
+        IoProt::READ | IoProt::CACHE,
+    )
+    .unwrap();
+    let pte = leaf(pt.root_bus_addr(), 0x4020_0000);
+    assert_eq!(pte & 3, 1);
+    assert_eq!((pte >> 2) & 7, 1, "cacheable attribute index");
+    assert_ne!(pte & (1 << 7), 0, "read only");
+    assert_eq!(pt.iova_to_phys(0x403f_ffff), Some(0x1_003f_ffff));
+    assert_eq!(pt.iova_to_phys(0x4040_0010), Some(0x1_0040_0010));
+
+    assert!(matches!(
+        pt.map(0x1000_1000, 0x9000_0000, 0x1000, IoProt::READ),
+        Err(DError::InvalidIova { .. })
+    ));
+    // a block can't be split
+    assert!(pt.unmap(0x4020_0000, 0x1000).is_err());
+
+    pt.unmap(0x1000_0000, 0x2000).unwrap();
+    assert_eq!(pt.iova_to_phys(0x1000_0000), None);
+    pt.unmap(0x4020_0000, 0x20_1000).unwrap();
+    assert_eq!(pt.iova_to_phys(0x4040_0000), None);
+
+    let mut s2 = IoPageTable::new(&dev, LpaeStage::Stage2).unwrap();
+    s2.map(
+        0x8000_0000,
+        0x8000_0000,
+        0x1000,
+        IoProt::READ | IoProt::MMIO,
+    )
+    .unwrap();
+    let pte = leaf(s2.root_bus_addr(), 0x8000_0000);
+    assert_eq!((pte >> 6) & 3, 1, "read only");
+    assert_eq!((pte >> 2) & 0xf, 1, "device memory");
+}
+
 struct Impled;
 
+static BOUNCE: BounceRegion = BounceRegion::new();
+
 impl Osal for Impled {
     fn map(&self, addr: std::ptr::NonNull<u8>, size: usize, direction: Direction) -> u64 {
         println!("map @{:?}, size {size:#x}, {direction:?}", addr);
@@ -150,4 +1000,31 @@ impl Osal for Impled {
     fn invalidate(&self, addr: std::ptr::NonNull<u8>, size: usize) {
         println!("invalidate @{:?}, size {size:#x}", addr);
     }
+
+    fn flush_invalidate(&self, addr: std::ptr::NonNull<u8>, size: usize) {
+        println!("flush invalidate @{:?}, size {size:#x}", addr);
+    }
+
+    unsafe fn alloc_coherent(
+        &self,
+        _dma_mask: u64,
+        layout: std::alloc::Layout,
+    ) -> Option<CoherentMem> {
+        assert_ne!(layout.size(), 0, "zero-size alloc_coherent");
+        let cpu_addr = NonNull::new(std::alloc::alloc_zeroed(layout))?;
+        println!("alloc coherent @{:?}, size {:#x}", cpu_addr, layout.size());
+        Some(CoherentMem {
+            cpu_addr,
+            bus_addr: cpu_addr.as_ptr() as usize as _,
+        })
+    }
+
+    unsafe fn dealloc_coherent(&self, mem: CoherentMem, layout: std::alloc::Layout) {
+        println!("dealloc coherent @{:?}", mem.cpu_addr);
+        std::alloc::dealloc(mem.cpu_addr.as_ptr(), layout)
+    }
+
+    fn bounce_region(&self) -> Option<&BounceRegion> {
+        Some(&BOUNCE)
+    }
 }
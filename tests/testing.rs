@@ -0,0 +1,315 @@
+use dma_api::{testing::*, *};
+
+static OSAL: RecordingOsal = RecordingOsal::new();
+
+#[test]
+fn test_recording() {
+    let dev = DmaDevice::new(&OSAL, u32::MAX as u64);
+
+    {
+        let mut dma: DVec<u8> = DVec::zeros_in(&dev, 0x40, 0x40, Direction::ToDevice).unwrap();
+        let cpu = std::ptr::NonNull::new(dma.as_ptr()).unwrap();
+        assert_ne!(dma.bus_addr(), cpu.as_ptr() as u64);
+        assert!(dma.bus_addr() >= OSAL.iova_base());
+
+        dma.set(0, 0xaa);
+
+        let mut buf = [0u8; 1];
+        OSAL.device_read(dma.bus_addr(), &mut buf);
+        assert_eq!(buf[0], 0xaa);
+        OSAL.assert_flushed_before_read(cpu, 1, dma.bus_addr());
+
+        let rx: DVec<u8> = DVec::zeros_in(&dev, 0x40, 0x40, Direction::FromDevice).unwrap();
+        OSAL.device_write(rx.bus_addr(), &[1, 2, 3]);
+        assert_eq!(rx.get(2), Some(3));
+        OSAL.assert_invalidated_after_write(
+            std::ptr::NonNull::new(rx.as_ptr()).unwrap(),
+            1,
+            rx.bus_addr(),
+        );
+    }
+
+    OSAL.assert_no_live_mappings();
+    assert!(OSAL
+        .events()
+        .iter()
+        .any(|e| matches!(e, Event::Dealloc { size: 0x40, .. })));
+}
+
+#[test]
+#[should_panic(expected = "not a mapped bus address")]
+fn test_cpu_pointer_as_bus_addr() {
+    static OSAL: RecordingOsal = RecordingOsal::new();
+    let dev = DmaDevice::new(&OSAL, u64::MAX);
+
+    let dma: DVec<u8> = DVec::zeros_in(&dev, 0x40, 0x40, Direction::ToDevice).unwrap();
+    let mut buf = [0u8; 4];
+    OSAL.device_read(dma.as_ptr() as u64, &mut buf);
+}
+
+#[test]
+fn test_cache_sim() {
+    static SIM: CacheSimOsal = CacheSimOsal::new();
+    let dev = DmaDevice::new(&SIM, u64::MAX);
+
+    let mut tx: DVec<u32> = DVec::zeros_in(&dev, 16, 0x40, Direction::ToDevice).unwrap();
+    let mut buf = [0xffu8; 4];
+    SIM.device_read(tx.bus_addr(), &mut buf);
+    assert_eq!(buf, [0; 4], "zeroing was flushed");
+
+    tx.set(0, 0x1234_5678);
+    SIM.device_read(tx.bus_addr(), &mut buf);
+    assert_eq!(u32::from_le_bytes(buf), 0x1234_5678);
+
+    // a write the driver forgot to flush stays in the cpu cache
+    unsafe { tx.as_ptr().add(1).write(7) };
+    SIM.device_read(tx.bus_addr() + 4, &mut buf);
+    assert_eq!(u32::from_le_bytes(buf), 0);
+
+    let rx: DVec<u8> = DVec::zeros_in(&dev, 64, 0x40, Direction::FromDevice).unwrap();
+    SIM.device_write(rx.bus_addr(), &[1, 2, 3]);
+    assert_eq!(
+        unsafe { rx.as_ptr().add(2).read() },
+        0,
+        "not invalidated yet"
+    );
+    assert_eq!(rx.get(2), Some(3));
+
+    let b: DBox<u64> = DBox::zero_in(&dev, Direction::Bidirectional).unwrap();
+    SIM.device_write(b.bus_addr(), &42u64.to_le_bytes());
+    assert_eq!(b.read(), 42);
+
+    // own the whole line, so no neighbouring stack data is written back over it
+    #[repr(align(64))]
+    struct Line([u8; 64]);
+    let data = Line([0; 64]);
+    let s = DSlice::from_in(&dev, &data.0, Direction::FromDevice).unwrap();
+    SIM.device_write(s.bus_addr(), &[9; 8]);
+    assert_eq!(&s.as_ref()[..8], &[9; 8]);
+}
+
+#[test]
+fn test_sync_per_direction() {
+    static OSAL: RecordingOsal = RecordingOsal::new();
+    let dev = DmaDevice::new(&OSAL, u64::MAX);
+
+    let rx: DVec<u8> = DVec::zeros_in(&dev, 0x80, 0x40, Direction::FromDevice).unwrap();
+    let addr = rx.as_ptr() as usize;
+    // the zeroed lines are cleaned and dropped before the device owns them
+    assert_eq!(
+        OSAL.events().last(),
+        Some(&Event::FlushInvalidate { addr, size: 0x80 })
+    );
+
+    OSAL.clear_events();
+    rx.sync_for_device(..);
+    rx.sync_for_cpu(0x40..);
+    assert_eq!(
+        OSAL.events(),
+        [
+            Event::FlushInvalidate { addr, size: 0x80 },
+            Event::Invalidate {
+                addr: addr + 0x40,
+                size: 0x40
+            },
+        ]
+    );
+
+    let tx: DVec<u8> = DVec::zeros_in(&dev, 0x40, 0x40, Direction::ToDevice).unwrap();
+    let addr = tx.as_ptr() as usize;
+    OSAL.clear_events();
+    tx.sync_for_device(..);
+    tx.sync_for_cpu(..);
+    assert_eq!(OSAL.events(), [Event::Flush { addr, size: 0x40 }]);
+}
+
+#[test]
+fn test_typed_direction() {
+    static OSAL: RecordingOsal = RecordingOsal::new();
+    let dev = DmaDevice::new(&OSAL, u64::MAX);
+
+    let mut tx: DVec<u8, ToDevice> = DVec::zeros_in(&dev, 0x40, 0x40, ToDevice).unwrap();
+    tx.set(0, 0xaa);
+    tx.copy_from_slice(&[0xaa; 0x40]);
+    let mut buf = [0u8; 1];
+    OSAL.device_read(tx.bus_addr(), &mut buf);
+    assert_eq!(buf[0], 0xaa);
+
+    let rx: DVec<u8, FromDevice> = DVec::zeros_in(&dev, 0x40, 0x40, FromDevice).unwrap();
+    OSAL.device_write(rx.bus_addr(), &[7]);
+    assert_eq!(rx.get(0), Some(7));
+    assert_eq!(rx[0], 7);
+
+    let mut b: DBox<u32, Bidirectional> = DBox::zero_in(&dev, Bidirectional).unwrap();
+    b.modify(|v| *v += 1);
+    assert_eq!(b.read(), 1);
+
+    let mut data = [0u8; 0x40];
+    let s = DSliceMut::from_in(&dev, &mut data, ToDevice).unwrap();
+    s.set(1, 5);
+    drop(s);
+    assert_eq!(data[1], 5);
+
+    // markers map with the matching runtime direction
+    let directions: Vec<_> = OSAL
+        .events()
+        .iter()
+        .filter_map(|e| match e {
+            Event::Map { direction, .. } => Some(*direction),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        directions,
+        [
+            Direction::ToDevice,
+            Direction::FromDevice,
+            Direction::Bidirectional,
+            Direction::ToDevice
+        ]
+    );
+}
+
+#[test]
+fn test_barriers() {
+    static OSAL: RecordingOsal = RecordingOsal::new();
+    let dev = DmaDevice::new(&OSAL, u64::MAX);
+    let dma: DVec<u32> = DVec::zeros_in(&dev, 0x10, 0x40, Direction::Bidirectional).unwrap();
+
+    // mapping already handed the zeroed buffer to the device once
+    let before = OSAL.barriers();
+    dma.confirm_write_all();
+    assert_eq!(OSAL.barriers().wmb, before.wmb + 1);
+
+    dma.prepare_read_all();
+    assert_eq!(OSAL.barriers().rmb, before.rmb + 1);
+}
+
+#[test]
+fn test_cache_line() {
+    static OSAL: RecordingOsal = RecordingOsal::new();
+    let dev = DmaDevice::new(&OSAL, u64::MAX);
+
+    let dma: DVec<u32> = DVec::zeros_in(&dev, 3, 4, Direction::FromDevice).unwrap();
+    let base = dma.as_ptr() as usize;
+    assert_eq!(base % 64, 0);
+    assert_eq!(dma.len(), 3);
+
+    OSAL.clear_events();
+    dma.get(1);
+    assert_eq!(
+        OSAL.events(),
+        [
+            Event::Flush {
+                addr: base,
+                size: 64
+            },
+            Event::Invalidate {
+                addr: base,
+                size: 64
+            }
+        ]
+    );
+
+    let strict = dev.with_strict_alignment(true);
+    let src = vec![0u8; 0x100];
+    let r = DSlice::from_in(&strict, &src[1..0x41], Direction::FromDevice);
+    assert!(matches!(r, Err(DError::Misaligned { line: 64, .. })));
+    assert!(DSlice::from_in(&strict, &src[1..0x41], Direction::ToDevice).is_ok());
+}
+
+#[test]
+fn test_sync_range() {
+    static OSAL: RecordingOsal = RecordingOsal::new().with_cache_line_size(1);
+    let dev = DmaDevice::new(&OSAL, u64::MAX);
+
+    let dma: DVec<u16> = DVec::zeros_in(&dev, 0x100, 0x40, Direction::FromDevice).unwrap();
+    let base = dma.as_ptr() as usize;
+
+    dma.sync_for_cpu(2..5);
+    assert_eq!(
+        OSAL.events().last(),
+        Some(&Event::Invalidate {
+            addr: base + 4,
+            size: 6
+        })
+    );
+
+    let src = [0u32; 0x10];
+    let addr = src.as_ptr() as usize;
+    let slice = DSlice::from_in(&dev, &src, Direction::FromDevice).unwrap();
+    slice.prepare_read_all();
+    assert_eq!(
+        OSAL.events().last(),
+        Some(&Event::Invalidate { addr, size: 0x40 })
+    );
+
+    slice.sync_for_cpu(..=1);
+    assert_eq!(
+        OSAL.events().last(),
+        Some(&Event::Invalidate { addr, size: 8 })
+    );
+}
+
+#[test]
+fn test_guards() {
+    static OSAL: RecordingOsal = RecordingOsal::new().with_cache_line_size(1);
+    let dev = DmaDevice::new(&OSAL, u64::MAX);
+    let flushes = || {
+        OSAL.events()
+            .into_iter()
+            .filter_map(|e| match e {
+                Event::Flush { addr, size } => Some((addr, size)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut dma: DVec<u8> = DVec::zeros_in(&dev, 0x40, 0x40, Direction::ToDevice).unwrap();
+    let base = dma.as_ptr() as usize;
+
+    OSAL.clear_events();
+    {
+        let mut w = dma.write_guard();
+        w[..4].copy_from_slice(b"head");
+        w.mark_dirty(..4);
+        assert!(flushes().is_empty());
+    }
+    assert_eq!(flushes(), [(base, 4)]);
+
+    assert_eq!(&dma.read_guard()[..4], b"head");
+
+    let mut src = [0u16; 8];
+    let addr = src.as_ptr() as usize;
+    let mut slice = DSliceMut::from_in(&dev, &mut src, Direction::ToDevice).unwrap();
+    slice.write_guard().fill(7);
+    assert_eq!(flushes().last(), Some(&(addr, 16)));
+    assert_eq!(slice.read_guard()[7], 7);
+}
+
+#[test]
+fn test_cache_line_from_osal() {
+    static OSAL: RecordingOsal = RecordingOsal::new().with_cache_line_size(128);
+    let dev = DmaDevice::new(&OSAL, u64::MAX);
+    assert_eq!(dev.cache_line_size(), 128);
+
+    let dma: DVec<u8> = DVec::zeros_in(&dev, 3, 4, Direction::FromDevice).unwrap();
+    assert_eq!(dma.as_ptr() as usize % 128, 0);
+
+    OSAL.clear_events();
+    dma.sync_for_cpu(1..2);
+    assert_eq!(
+        OSAL.events().last(),
+        Some(&Event::Invalidate {
+            addr: dma.as_ptr() as usize,
+            size: 128
+        })
+    );
+}
+
+#[test]
+#[should_panic(expected = "power of two")]
+fn test_cache_line_not_power_of_two() {
+    static OSAL: RecordingOsal = RecordingOsal::new().with_cache_line_size(48);
+    DmaDevice::new(&OSAL, u64::MAX);
+}